@@ -0,0 +1,44 @@
+/// Write a few seconds of granular velvet texture: short Hann-windowed velvet grains triggered
+/// at OVN-spaced onsets
+use std::env;
+
+use velvet_noise::granular::{hann_window, Grain, GranularVelvet};
+use velvet_noise::{Choice, OVNImpulseLocations, VelvetNoiseKernel};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        println!("Usage: ./granular <wav out>");
+        return;
+    }
+
+    let sample_rate = 44100;
+    let n_seconds = 5;
+
+    // 20ms grains of dense velvet noise
+    let grain_samples = sample_rate / 50;
+    let kernel = VelvetNoiseKernel::new(
+        OVNImpulseLocations::new(4000, sample_rate),
+        Choice::classic(),
+    )
+    .render(0, grain_samples, 1.);
+    let grain = Grain::new(&kernel, &hann_window(grain_samples));
+
+    // 30 grains per second
+    let mut granular = GranularVelvet::new(grain, 30, sample_rate);
+
+    // try not to clip any samples
+    let output_gain = 0.1;
+
+    let block_size = 512;
+    let samples = (0..)
+        .flat_map(|_| granular.render(block_size))
+        .map(|x| x * output_gain);
+    velvet_noise::wav::write_velvet_wav(
+        args[1].as_str(),
+        samples,
+        sample_rate * n_seconds,
+        sample_rate as u32,
+    )
+    .unwrap();
+}
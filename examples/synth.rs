@@ -0,0 +1,40 @@
+/// Play a note on a simple subtractive synth with velvet noise as its source: the noise is
+/// coloured with pink and low pass filters, then shaped by an ADSR envelope
+use std::env;
+
+use velvet_noise::envelope::Adsr;
+use velvet_noise::filters::{Biquad, Pink};
+use velvet_noise::OriginalVelvetNoise;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 4 {
+        println!("Usage: ./synth <wav out> <density> <cutoff hz>");
+        return;
+    }
+    let density: f32 = args[2].parse().expect("density must be a number");
+    let cutoff: f32 = args[3].parse().expect("cutoff must be a number");
+
+    let sample_rate = 44100;
+
+    // Source
+    let noise = OriginalVelvetNoise::new(density, sample_rate as f32, None);
+
+    // Filters: pink for a warmer noise floor, then a resonant low pass
+    let pink = Pink::new().apply(noise);
+    let filtered = Biquad::lowpass(cutoff, 4., sample_rate as f32).apply(pink);
+
+    // Hold the note for one second
+    let adsr = Adsr::new(0.01, 0.2, 0.6, 0.8, sample_rate);
+    let gate_samples = sample_rate;
+    let note = adsr.render(gate_samples).len();
+
+    // try not to clip any samples
+    let output_gain = 0.5;
+    let samples = adsr
+        .apply(filtered, gate_samples)
+        .map(|x| (x * output_gain).clamp(-1., 1.));
+
+    velvet_noise::wav::write_velvet_wav(args[1].as_str(), samples, note, sample_rate as u32)
+        .unwrap();
+}
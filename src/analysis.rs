@@ -0,0 +1,480 @@
+//! Measurements and statistics of velvet noise and rendered kernels
+
+use crate::fft::kernel_spectrum;
+use crate::granular::hann_window;
+use crate::kernel::sparsify;
+use crate::LocationKind;
+
+/// Non-zero samples per second in a block of velvet noise.
+/// Counts impulses rather than summing magnitudes, so it also works for scaled impulses.
+pub fn observed_density(samples: &[f32], sample_rate: usize) -> f32 {
+    let impulses = samples.iter().filter(|x| **x != 0.).count();
+    impulses as f32 * sample_rate as f32 / samples.len() as f32
+}
+
+/// Number of non-zero samples among the next `n` samples of a signal, without collecting them
+pub fn impulse_count(gen: impl Iterator<Item = f32>, n: usize) -> usize {
+    gen.take(n).filter(|x| *x != 0.).count()
+}
+
+/// Multiply-adds needed per output sample when convolving with a sparse kernel, i.e. its tap
+/// count
+pub fn estimated_taps_per_sample(kernel: &[(usize, f32)]) -> usize {
+    kernel.len()
+}
+
+/// Multiply-adds per second of audio when convolving with a sparse kernel, for real-time
+/// budgeting
+pub fn estimated_macs_per_second(kernel: &[(usize, f32)], sample_rate: usize) -> usize {
+    estimated_taps_per_sample(kernel) * sample_rate
+}
+
+/// Centroid delay of a kernel in samples, `sum(index * |coef|) / sum(|coef|)`, for reporting
+/// the latency it adds on average. 0 for an empty or all-zero kernel.
+pub fn group_delay_samples(kernel: &[(usize, f32)]) -> f32 {
+    let weight: f32 = kernel.iter().map(|(_idx, coeff)| coeff.abs()).sum();
+    if weight == 0. {
+        return 0.;
+    }
+    let moment: f32 = kernel
+        .iter()
+        .map(|(idx, coeff)| *idx as f32 * coeff.abs())
+        .sum();
+    moment / weight
+}
+
+/// Measured density, in impulses per second, of each consecutive window of `window_seconds`
+/// covered by sorted impulse `locations`, e.g. to spot density drifting over a long render.
+/// The last, partial window is left out.
+pub fn density_drift(locations: &[usize], sample_rate: usize, window_seconds: usize) -> Vec<f32> {
+    let window = sample_rate * window_seconds;
+    let n_windows = locations.last().map_or(0, |last| (last + 1) / window);
+    let mut counts = vec![0usize; n_windows];
+    for idx in locations {
+        if let Some(count) = counts.get_mut(idx / window) {
+            *count += 1;
+        }
+    }
+    counts
+        .into_iter()
+        .map(|count| count as f32 / window_seconds as f32)
+        .collect()
+}
+
+/// RMS of a kernel over its length up to and including the last tap,
+/// `sqrt(sum of squares / length)`, for gain staging against white noise. Unit-variance white
+/// noise scaled by this value is as loud as the kernel, and dividing the kernel by it gives
+/// unit variance. 0 for an empty kernel.
+pub fn equivalent_white_gain(kernel: &[(usize, f32)]) -> f32 {
+    let length = match kernel.iter().map(|(idx, _coeff)| idx + 1).max() {
+        Some(length) => length,
+        None => return 0.,
+    };
+    let sum_of_squares: f32 = kernel.iter().map(|(_idx, coeff)| coeff * coeff).sum();
+    (sum_of_squares / length as f32).sqrt()
+}
+
+/// Power spectrum of a kernel from bin 1 up to, but excluding, Nyquist
+fn power_spectrum(kernel: &[(usize, f32)], fft_size: usize) -> Vec<f32> {
+    assert!(
+        fft_size >= 4,
+        "fft_size must be at least 4 to leave a bin between DC and Nyquist"
+    );
+    kernel_spectrum(kernel, fft_size)[1..fft_size / 2]
+        .iter()
+        .map(|x| x.norm() * x.norm())
+        .collect()
+}
+
+/// Spectral flatness of a kernel, the geometric mean of its power spectrum over the arithmetic
+/// mean. 1 for a perfectly flat spectrum, approaching 0 for a tonal one. DC and Nyquist are
+/// excluded; taps beyond `fft_size` are ignored. `fft_size` must be at least 4.
+pub fn spectral_flatness(kernel: &[(usize, f32)], fft_size: usize) -> f32 {
+    let power = power_spectrum(kernel, fft_size);
+    let arithmetic = power.iter().sum::<f32>() / power.len() as f32;
+    if arithmetic == 0. {
+        return 0.;
+    }
+    let log_mean = power
+        .iter()
+        .map(|p| p.max(f32::MIN_POSITIVE).ln())
+        .sum::<f32>()
+        / power.len() as f32;
+    log_mean.exp() / arithmetic
+}
+
+/// Spectral flatness of successive Hann-windowed frames of `window` samples, `hop` samples
+/// apart, e.g. to check that time-varying velvet noise stays white throughout. Frames run while
+/// they fit entirely within `ir`. See `spectral_flatness`. `window` must be at least 4 and
+/// `hop` non-zero.
+pub fn time_frequency_flatness(ir: &[f32], window: usize, hop: usize) -> Vec<f32> {
+    assert!(window >= 4, "window must be at least 4 samples");
+    assert!(hop > 0, "hop must be non-zero");
+    let hann = hann_window(window);
+    let fft_size = window.next_power_of_two();
+    (0..ir.len().saturating_sub(window) + 1)
+        .step_by(hop)
+        .filter(|start| start + window <= ir.len())
+        .map(|start| {
+            let frame: Vec<f32> = ir[start..start + window]
+                .iter()
+                .zip(&hann)
+                .map(|(x, w)| x * w)
+                .collect();
+            spectral_flatness(&sparsify(&frame), fft_size)
+        })
+        .collect()
+}
+
+/// Slope in dB per octave of a least squares line through the kernel's log-magnitude spectrum
+/// against log-frequency. 0 for white, about -3 for pink. DC and Nyquist are excluded; taps
+/// beyond `fft_size` are ignored. `fft_size` must be at least 4.
+pub fn spectral_tilt(kernel: &[(usize, f32)], fft_size: usize) -> f32 {
+    let points: Vec<(f32, f32)> = power_spectrum(kernel, fft_size)
+        .iter()
+        .enumerate()
+        .map(|(k, p)| {
+            let octave = ((k + 1) as f32).log2();
+            (octave, 10. * p.max(f32::MIN_POSITIVE).log10())
+        })
+        .collect();
+
+    let n = points.len() as f32;
+    let mean_x = points.iter().map(|(x, _y)| x).sum::<f32>() / n;
+    let mean_y = points.iter().map(|(_x, y)| y).sum::<f32>() / n;
+    let covariance: f32 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f32 = points.iter().map(|(x, _y)| (x - mean_x).powi(2)).sum();
+    covariance / variance
+}
+
+/// Expected mean and variance of the number of impulses falling in a window of
+/// `window_samples` samples.
+///
+/// OVN windows are assumed to start on a grid boundary, so only the final partial grid window
+/// contributes variance. ARN is treated as a renewal process with uniformly distributed
+/// spacing, and TRVN as an independent Bernoulli trial per sample.
+pub fn impulse_count_stats(
+    kind: LocationKind,
+    window_samples: usize,
+    sample_rate: usize,
+) -> (f32, f32) {
+    let window = window_samples as f32;
+    let sample_rate = sample_rate as f32;
+    match kind {
+        LocationKind::Original { density } => {
+            let td = (sample_rate as usize / density) as f32;
+            let partial = (window % td) / td;
+            (window / td, partial * (1. - partial))
+        }
+        LocationKind::Additive { density, delta } => {
+            let td = sample_rate / density;
+            let spread = 2. * delta * (td - 1.);
+            let spacing_variance = spread * spread / 12.;
+            (window / td, window * spacing_variance / td.powi(3))
+        }
+        LocationKind::TotallyRandom { density } => {
+            let p = density / sample_rate;
+            (window * p, window * p * (1. - p))
+        }
+    }
+}
+
+/// Schroeder backward-integrated energy decay curve in dB, normalised to start at 0 dB.
+/// Samples after the last non-zero sample are `-inf`, as is the whole curve for a silent IR.
+pub fn energy_decay_curve(ir: &[f32]) -> Vec<f32> {
+    let mut remaining: Vec<f32> = ir
+        .iter()
+        .rev()
+        .scan(0., |energy, x| {
+            *energy += x * x;
+            Some(*energy)
+        })
+        .collect();
+    remaining.reverse();
+
+    let total = remaining.first().cloned().unwrap_or(0.);
+    remaining
+        .into_iter()
+        .map(|energy| 10. * (energy / total).log10())
+        .map(|db| if db.is_nan() { f32::NEG_INFINITY } else { db })
+        .collect()
+}
+
+/// Reverberation time in seconds, estimated from the slope of a least squares fit to the
+/// energy decay curve between -5 and -35 dB, extrapolated to 60 dB of decay (T30).
+/// Returns NaN if the IR doesn't decay by at least 35 dB.
+pub fn rt60(ir: &[f32], sample_rate: usize) -> f32 {
+    let edc = energy_decay_curve(ir);
+    if !edc.iter().any(|db| *db <= -35.) {
+        return f32::NAN;
+    }
+
+    let points: Vec<(f32, f32)> = edc
+        .iter()
+        .enumerate()
+        .filter(|(_n, db)| **db <= -5. && **db >= -35.)
+        .map(|(n, db)| (n as f32 / sample_rate as f32, *db))
+        .collect();
+
+    let count = points.len() as f32;
+    let mean_t = points.iter().map(|(t, _db)| t).sum::<f32>() / count;
+    let mean_db = points.iter().map(|(_t, db)| db).sum::<f32>() / count;
+    let covariance: f32 = points
+        .iter()
+        .map(|(t, db)| (t - mean_t) * (db - mean_db))
+        .sum();
+    let variance: f32 = points.iter().map(|(t, _db)| (t - mean_t).powi(2)).sum();
+
+    let slope = covariance / variance;
+    -60. / slope
+}
+
+/// Normalised autocorrelation for lags `0..=max_lag`, so lag 0 is 1 for any non-silent IR
+pub fn autocorrelation(ir: &[f32], max_lag: usize) -> Vec<f32> {
+    cross_correlation(ir, ir, max_lag).split_off(max_lag)
+}
+
+/// Normalised cross-correlation for lags `-max_lag..=max_lag`, where a positive lag delays `b`
+/// relative to `a`. Values are in `[-1, 1]`, and all 0 if either input is silent.
+pub fn cross_correlation(a: &[f32], b: &[f32], max_lag: usize) -> Vec<f32> {
+    let energy = |x: &[f32]| x.iter().map(|s| s * s).sum::<f32>();
+    let norm = (energy(a) * energy(b)).sqrt();
+
+    (-(max_lag as isize)..=max_lag as isize)
+        .map(|lag| {
+            let sum: f32 = a
+                .iter()
+                .enumerate()
+                .filter_map(|(n, x)| {
+                    let m = n as isize + lag;
+                    if m < 0 {
+                        return None;
+                    }
+                    b.get(m as usize).map(|y| x * y)
+                })
+                .sum();
+            if norm == 0. {
+                0.
+            } else {
+                sum / norm
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::densify;
+    use crate::{Choice, OVNImpulseLocations, OriginalVelvetNoise, VelvetNoiseKernel};
+    use more_asserts::*;
+
+    #[test]
+    fn ovn_count_varies_least() {
+        let ovn = impulse_count_stats(LocationKind::Original { density: 441 }, 1000, 44100);
+        assert_eq!(ovn, (10., 0.));
+
+        let trvn = impulse_count_stats(LocationKind::TotallyRandom { density: 441. }, 1000, 44100);
+        assert_eq!(trvn.0, 10.);
+        assert_gt!(trvn.1, 1.);
+
+        let arn = impulse_count_stats(
+            LocationKind::Additive {
+                density: 441.,
+                delta: 0.5,
+            },
+            1000,
+            44100,
+        );
+        assert_eq!(arn.0, 10.);
+        assert_gt!(arn.1, ovn.1);
+    }
+
+    #[test]
+    fn edc_of_decaying_kernel_is_non_increasing() {
+        let sample_rate = 44100;
+        let length = sample_rate / 2;
+        let kernel: Vec<(usize, f32)> = VelvetNoiseKernel::new(
+            OVNImpulseLocations::new(2000, sample_rate),
+            Choice::classic(),
+        )
+        .render(0, length, 1.)
+        .into_iter()
+        .map(|(idx, coeff)| (idx, coeff * (-(idx as f32) / 4410.).exp()))
+        .collect();
+
+        let edc = energy_decay_curve(&densify(&kernel, length));
+        assert_eq!(edc.len(), length);
+        assert_eq!(edc[0], 0.);
+        for pair in edc.windows(2) {
+            assert_le!(pair[1], pair[0]);
+        }
+        assert_lt!(edc[length / 2], -20.);
+    }
+
+    #[test]
+    fn rt60_of_exponential_decay() {
+        let sample_rate = 44100;
+        let target = 0.5;
+        let ir: Vec<f32> = OriginalVelvetNoise::new(4000., sample_rate as f32, None)
+            .take(sample_rate)
+            .enumerate()
+            .map(|(n, x)| x * 10f32.powf(-3. * n as f32 / (target * sample_rate as f32)))
+            .collect();
+
+        assert_lt!((rt60(&ir, sample_rate) - target).abs(), 0.025);
+        assert!(rt60(&[1., 0.5], sample_rate).is_nan());
+    }
+
+    #[test]
+    fn independent_kernels_are_decorrelated() {
+        let sample_rate = 44100;
+        let render = |seed| {
+            let kernel = VelvetNoiseKernel::new(
+                OVNImpulseLocations::from_seed(2000, sample_rate, seed),
+                Choice::from_seed(0.5, seed + 1),
+            )
+            .render(0, sample_rate, 1.);
+            densify(&kernel, sample_rate)
+        };
+        let a = render(1);
+        let b = render(100);
+
+        let auto = autocorrelation(&a, 64);
+        assert_eq!(auto.len(), 65);
+        assert!((auto[0] - 1.).abs() < 1e-5);
+        assert!(auto[1..].iter().all(|x| x.abs() < 0.1));
+
+        let cross = cross_correlation(&a, &b, 64);
+        assert_eq!(cross.len(), 129);
+        assert!(cross.iter().all(|x| x.abs() < 0.1));
+    }
+
+    #[test]
+    fn observed_density_of_generated_block() {
+        let samples: Vec<f32> = OriginalVelvetNoise::new(2000., 96000., None)
+            .map(|x| x * 0.3)
+            .take(96000)
+            .collect();
+        assert_eq!(observed_density(&samples, 96000), 2000.);
+        assert_eq!(observed_density(&samples[..48000], 96000), 2000.);
+    }
+
+    #[test]
+    fn impulse_count_of_generated_block() {
+        let noise = OriginalVelvetNoise::new(2000., 96000., None).map(|x| x * 0.3);
+        assert_eq!(impulse_count(noise, 96000), 2000);
+    }
+
+    #[test]
+    fn convolution_cost() {
+        let kernel = VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(441, 44100, 0),
+            Choice::classic(),
+        )
+        .render(0, 44100, 1.);
+        assert_eq!(estimated_taps_per_sample(&kernel), 441);
+        assert_eq!(estimated_macs_per_second(&kernel, 44100), 441 * 44100);
+    }
+
+    #[test]
+    fn group_delay_of_clustered_kernel() {
+        let kernel = [(998, 1.), (999, -0.5), (1000, -1.), (1001, 0.5), (1002, 1.)];
+        assert_eq!(group_delay_samples(&kernel), 1000.);
+        assert_eq!(group_delay_samples(&[(0, 1.), (10, -1.), (40, 2.)]), 22.5);
+        assert_eq!(group_delay_samples(&[]), 0.);
+    }
+
+    #[test]
+    fn velvet_frames_are_flat() {
+        let ir: Vec<f32> = OriginalVelvetNoise::new(2000., 44100., None)
+            .take(44100)
+            .collect();
+        let frames = time_frequency_flatness(&ir, 1024, 512);
+        assert_eq!(frames.len(), 85);
+        assert!(frames.iter().all(|flatness| *flatness > 0.3));
+
+        // a sine is far from flat
+        let tone: Vec<f32> = (0..4096).map(|n| (n as f32 * 0.3).sin()).collect();
+        assert!(time_frequency_flatness(&tone, 1024, 512)
+            .iter()
+            .all(|flatness| *flatness < 0.1));
+    }
+
+    #[test]
+    fn density_drift_of_long_render() {
+        let ovn: Vec<usize> = OVNImpulseLocations::from_seed(2000, 48000, 1)
+            .take_while(|idx| *idx < 48000 * 200)
+            .collect();
+        let drift = density_drift(&ovn, 48000, 10);
+        assert_eq!(drift.len(), 19);
+        assert!(drift.iter().all(|density| *density == 2000.));
+
+        // impulse times accumulated in f32 seconds lose precision as the time grows
+        let mut time = 0f32;
+        let buggy: Vec<usize> = (0..400_000)
+            .map(|_| {
+                time += 1. / 2000.;
+                (time * 48000.) as usize
+            })
+            .collect();
+        let drift = density_drift(&buggy, 48000, 10);
+        let (first, last) = (drift[0], drift[drift.len() - 1]);
+        assert!((first - 2000.).abs() < 1.);
+        assert!((last - 2000.).abs() > 10.);
+    }
+
+    #[test]
+    fn white_gain_of_known_kernel() {
+        assert_eq!(
+            equivalent_white_gain(&[(0, 1.), (3, -1.), (7, 1.), (15, -1.)]),
+            0.5
+        );
+        assert_eq!(equivalent_white_gain(&[(4, 2.)]), 2. / 5f32.sqrt());
+        assert_eq!(equivalent_white_gain(&[]), 0.);
+
+        // a velvet kernel with one impulse every td samples ends near its last window
+        let kernel = VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(441, 44100, 2),
+            Choice::from_seed(0.5, 3),
+        )
+        .render(0, 44100, 1.);
+        assert!((equivalent_white_gain(&kernel) - 0.1).abs() < 0.001);
+    }
+
+    #[test]
+    #[should_panic]
+    fn spectral_flatness_rejects_tiny_fft() {
+        spectral_flatness(&[(0, 1.)], 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn time_frequency_flatness_rejects_empty_window() {
+        time_frequency_flatness(&[0.; 100], 0, 10);
+    }
+
+    #[test]
+    fn spectral_tilt_of_pink_kernel() {
+        let sample_rate = 44100;
+        let kernel = VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(4410, sample_rate, 12),
+            Choice::from_seed(0.5, 13),
+        )
+        .render(0, 16384, 1.);
+        let pink: Vec<f32> = crate::filters::Pink::new()
+            .apply(densify(&kernel, 16384).into_iter())
+            .collect();
+        let pink = crate::kernel::sparsify(&pink);
+
+        assert_lt!(spectral_tilt(&kernel, 16384).abs(), 0.3);
+        assert_lt!((spectral_tilt(&pink, 16384) + 3.).abs(), 0.5);
+        assert_gt!(
+            spectral_flatness(&kernel, 16384),
+            spectral_flatness(&pink, 16384)
+        );
+    }
+}
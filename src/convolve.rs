@@ -0,0 +1,424 @@
+//! Sparse convolution with velvet kernels
+
+use crate::Choice;
+#[cfg(feature = "dasp")]
+use dasp_frame::Frame;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+/// A kernel whose taps slide along a delay line by one sample per `advance`.
+/// Taps that fall off the end are respawned at index 0 with a fresh coefficient, so the number
+/// of live taps stays constant. Used for signal extrapolation as described in
+/// http://dafx.de/paper-archive/2018/papers/DAFx2018_paper_11.pdf
+#[derive(Clone, Debug)]
+pub struct MovingKernel {
+    taps: Vec<(usize, f32)>,
+    max_index: usize,
+    choice: Choice,
+}
+
+impl MovingKernel {
+    /// taps is the initial kernel, all with indices <= max_index
+    /// choice provides coefficients for respawned taps
+    pub fn new(taps: Vec<(usize, f32)>, max_index: usize, choice: Choice) -> Self {
+        Self {
+            taps,
+            max_index,
+            choice,
+        }
+    }
+
+    /// Current taps
+    pub fn taps(&self) -> &[(usize, f32)] {
+        &self.taps
+    }
+
+    /// Move all taps along the delay line, respawning any that fall off the end
+    pub fn advance(&mut self) {
+        for tap in self.taps.iter_mut() {
+            *tap = (tap.0 + 1, tap.1);
+        }
+
+        let max_index = self.max_index;
+        let length_before = self.taps.len();
+        self.taps.retain(|&tap| tap.0 <= max_index);
+        let length_after = self.taps.len();
+        for _ in 0..(length_before - length_after) {
+            self.taps.push((0, self.choice.next().unwrap()));
+        }
+    }
+
+    /// Convolve the current taps with samples, which must have more than max_index elements
+    pub fn convolve(&self, samples: &[f32]) -> f32 {
+        self.taps.iter().map(|(i, x)| samples[*i] * x).sum()
+    }
+}
+
+/// Convolve input with a sparse kernel, writing `output.len()` samples.
+/// Input beyond its end is treated as silence, so output may be longer than input to capture
+/// the tail.
+pub fn convolve(input: &[f32], kernel: &[(usize, f32)], output: &mut [f32]) {
+    output.fill(0.);
+    for (idx, coeff) in kernel.iter() {
+        for (out, x) in output.iter_mut().skip(*idx).zip(input.iter()) {
+            *out += x * coeff;
+        }
+    }
+}
+
+/// As `convolve`, mixed with the dry input as `(1 - wet) * dry + wet * convolved`. The dry
+/// signal is aligned with index 0 of the kernel and is silent beyond the end of the input.
+pub fn convolve_mix(input: &[f32], kernel: &[(usize, f32)], wet: f32, output: &mut [f32]) {
+    if wet == 0. {
+        output.fill(0.);
+        for (out, x) in output.iter_mut().zip(input.iter()) {
+            *out = *x;
+        }
+        return;
+    }
+
+    convolve(input, kernel, output);
+    for (n, out) in output.iter_mut().enumerate() {
+        let dry = input.get(n).cloned().unwrap_or(0.);
+        *out = (1. - wet) * dry + wet * *out;
+    }
+}
+
+/// Apply the same mono kernel to both channels of a stereo input
+pub fn convolve_stereo_mono_kernel(
+    in_l: &[f32],
+    in_r: &[f32],
+    kernel: &[(usize, f32)],
+    out_l: &mut [f32],
+    out_r: &mut [f32],
+) {
+    convolve(in_l, kernel, out_l);
+    convolve(in_r, kernel, out_r);
+}
+
+/// Convolve one input with each of several kernels, returning the full convolution for each,
+/// i.e. the input length plus the kernel's last index. Kernels are processed in parallel with
+/// the `rayon` feature.
+pub fn convolve_many(input: &[f32], kernels: &[Vec<(usize, f32)>]) -> Vec<Vec<f32>> {
+    let convolve_one = |kernel: &Vec<(usize, f32)>| {
+        let tail = kernel.iter().map(|(idx, _coeff)| *idx).max().unwrap_or(0);
+        let mut output = vec![0.; input.len() + tail];
+        convolve(input, kernel, &mut output);
+        output
+    };
+
+    #[cfg(feature = "rayon")]
+    return kernels.par_iter().map(convolve_one).collect();
+
+    #[cfg(not(feature = "rayon"))]
+    return kernels.iter().map(convolve_one).collect();
+}
+
+/// Block processing shared by the streaming convolvers and reverb, so implementations can be
+/// swapped behind a `Box<dyn VelvetProcessor>`
+pub trait VelvetProcessor {
+    /// Process `input` into `output`, which must be the same length, continuing from previous
+    /// calls
+    fn process(&mut self, input: &[f32], output: &mut [f32]);
+}
+
+/// Smallest delay line that holds every tap of `kernel` without wrapping around, i.e. its
+/// largest index plus one. An empty kernel still needs one sample.
+pub fn required_buffer_size(kernel: &[(usize, f32)]) -> usize {
+    kernel
+        .iter()
+        .map(|(idx, _coeff)| idx + 1)
+        .max()
+        .unwrap_or(1)
+}
+
+/// Streaming convolution of single samples with a sparse kernel
+#[derive(Clone, Debug)]
+pub struct RingConvolver {
+    kernel: Vec<(usize, f32)>,
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl RingConvolver {
+    /// buffer_size defaults to `required_buffer_size(&kernel)` when `None`. A larger buffer
+    /// can be given to share a size with other convolvers.
+    ///
+    /// Panics if buffer_size is too small for the kernel.
+    pub fn new(kernel: Vec<(usize, f32)>, buffer_size: Option<usize>) -> Self {
+        let required = required_buffer_size(&kernel);
+        let length = buffer_size.unwrap_or(required);
+        assert!(
+            length >= required,
+            "buffer of {} samples is too small for a kernel needing {}",
+            length,
+            required
+        );
+        Self {
+            kernel,
+            buffer: vec![0.; length],
+            position: 0,
+        }
+    }
+
+    /// Kernel being convolved
+    pub fn kernel(&self) -> &[(usize, f32)] {
+        &self.kernel
+    }
+
+    /// Push one input sample and return the next output sample
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let length = self.buffer.len();
+        self.position = (self.position + 1) % length;
+        self.buffer[self.position] = sample;
+
+        let (buffer, position) = (&self.buffer, self.position);
+        self.kernel
+            .iter()
+            .map(|(idx, coeff)| buffer[(position + length - idx) % length] * coeff)
+            .sum()
+    }
+}
+
+impl VelvetProcessor for RingConvolver {
+    fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        assert_eq!(input.len(), output.len());
+        for (out, x) in output.iter_mut().zip(input.iter()) {
+            *out = RingConvolver::process(self, *x);
+        }
+    }
+}
+
+/// Convolve frames with a sparse kernel, where each index addresses a frame of samples
+#[cfg(feature = "dasp")]
+pub fn convolve_kern<F: Frame<Sample = f32>>(samples: &[F], kern: &[(usize, f32)]) -> F {
+    accumulate_frames(kern, |i| samples[i])
+}
+
+/// Sum of the frames returned by `frame_at` for each tap index, scaled by the tap coefficient
+#[cfg(feature = "dasp")]
+fn accumulate_frames<F: Frame<Sample = f32>>(
+    kern: &[(usize, f32)],
+    frame_at: impl Fn(usize) -> F,
+) -> F {
+    kern.iter().fold(F::EQUILIBRIUM, |accumulator, (i, x)| {
+        accumulator.add_amp(frame_at(*i).scale_amp(*x))
+    })
+}
+
+/// Streaming convolution of multichannel frames with a mono sparse kernel
+#[cfg(feature = "dasp")]
+#[derive(Clone, Debug)]
+pub struct FrameConvolver<F> {
+    kernel: Vec<(usize, f32)>,
+    buffer: Vec<F>,
+    position: usize,
+}
+
+#[cfg(feature = "dasp")]
+impl<F: Frame<Sample = f32>> FrameConvolver<F> {
+    /// The delay line is `required_buffer_size(&kernel)` frames long
+    pub fn new(kernel: Vec<(usize, f32)>) -> Self {
+        let length = required_buffer_size(&kernel);
+        Self {
+            kernel,
+            buffer: vec![F::EQUILIBRIUM; length],
+            position: 0,
+        }
+    }
+
+    /// Push one input frame and return the next output frame
+    pub fn process(&mut self, frame: F) -> F {
+        let length = self.buffer.len();
+        self.position = (self.position + 1) % length;
+        self.buffer[self.position] = frame;
+
+        let (buffer, position) = (&self.buffer, self.position);
+        accumulate_frames(&self.kernel, |i| buffer[(position + length - i) % length])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::densify;
+    use crate::{OVNImpulseLocations, VelvetNoiseKernel};
+
+    #[test]
+    fn moving_kernel_keeps_density() {
+        let density = 100;
+        let sample_rate = 1000;
+        let length = 1000;
+        let taps = VelvetNoiseKernel::new(
+            OVNImpulseLocations::new(density, sample_rate),
+            Choice::classic(),
+        )
+        .render(0, length, 1.);
+        assert_eq!(taps.len(), density);
+
+        let mut kernel = MovingKernel::new(taps, length - 1, Choice::classic());
+        let samples = vec![1f32; length];
+        for _ in 0..(10 * length) {
+            kernel.advance();
+            assert_eq!(kernel.taps().len(), density);
+            assert!(kernel.taps().iter().all(|(i, _)| *i < length));
+            assert!(kernel.convolve(&samples).abs() <= density as f32);
+        }
+    }
+
+    /// Direct convolution with a dense impulse response
+    fn dense_convolve(input: &[f32], ir: &[f32], length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|n| {
+                (0..=n)
+                    .filter(|k| *k < ir.len() && n - k < input.len())
+                    .map(|k| ir[k] * input[n - k])
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stereo_input_mono_kernel() {
+        let in_l: Vec<f32> = (0..64).map(|n| (n as f32 * 0.3).sin()).collect();
+        let in_r: Vec<f32> = (0..64).map(|n| (n as f32 * 0.7).cos()).collect();
+        let kernel = vec![(0, 1.), (5, -0.5), (17, 0.25), (40, -1.)];
+        let ir = densify(&kernel, 41);
+
+        let mut out_l = vec![0f32; 110];
+        let mut out_r = vec![0f32; 110];
+        convolve_stereo_mono_kernel(&in_l, &in_r, &kernel, &mut out_l, &mut out_r);
+
+        for (a, b) in out_l.iter().zip(dense_convolve(&in_l, &ir, 110)) {
+            assert!((a - b).abs() < 1e-5);
+        }
+        for (a, b) in out_r.iter().zip(dense_convolve(&in_r, &ir, 110)) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn ring_convolver_matches_convolve() {
+        let input: Vec<f32> = (0..200).map(|n| (n as f32 * 0.1).sin()).collect();
+        let kernel = vec![(0, 0.5), (3, -1.), (20, 0.25), (64, 1.)];
+
+        let mut expected = vec![0f32; input.len()];
+        convolve(&input, &kernel, &mut expected);
+
+        let mut convolver = RingConvolver::new(kernel, None);
+        for (x, y) in input.iter().zip(expected) {
+            assert!((convolver.process(*x) - y).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn buffer_size_covers_max_index() {
+        let kernel = vec![(0, 1.), (2205, -1.), (4410, 1.)];
+        assert_eq!(required_buffer_size(&kernel), 4411);
+        assert_eq!(required_buffer_size(&[]), 1);
+
+        let input: Vec<f32> = (0..5000).map(|n| (n as f32 * 0.01).sin()).collect();
+        let mut exact = RingConvolver::new(kernel.clone(), None);
+        let mut larger = RingConvolver::new(kernel, Some(8192));
+        for x in input {
+            assert_eq!(exact.process(x), larger.process(x));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn buffer_size_too_small() {
+        RingConvolver::new(vec![(0, 1.), (4410, 1.)], Some(4410));
+    }
+
+    #[cfg(feature = "dasp")]
+    #[test]
+    fn frame_convolver_is_per_channel() {
+        let in_l: Vec<f32> = (0..200).map(|n| (n as f32 * 0.1).sin()).collect();
+        let in_r: Vec<f32> = (0..200).map(|n| (n as f32 * 0.37).cos()).collect();
+        let kernel = vec![(0, 0.5), (3, -1.), (20, 0.25), (64, 1.)];
+
+        let mut out_l = vec![0f32; 200];
+        let mut out_r = vec![0f32; 200];
+        convolve_stereo_mono_kernel(&in_l, &in_r, &kernel, &mut out_l, &mut out_r);
+
+        let mut convolver = FrameConvolver::<[f32; 2]>::new(kernel);
+        for n in 0..200 {
+            let [l, r] = convolver.process([in_l[n], in_r[n]]);
+            assert!((l - out_l[n]).abs() < 1e-5);
+            assert!((r - out_r[n]).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn convolve_many_matches_serial() {
+        let input: Vec<f32> = (0..500).map(|n| (n as f32 * 0.05).sin()).collect();
+        let kernels: Vec<Vec<(usize, f32)>> = (0..8)
+            .map(|seed| {
+                VelvetNoiseKernel::new(
+                    OVNImpulseLocations::from_seed(2000, 44100, seed),
+                    Choice::from_seed(0.5, seed),
+                )
+                .render(0, 1000, 0.5)
+            })
+            .collect();
+
+        let outputs = convolve_many(&input, &kernels);
+        assert_eq!(outputs.len(), kernels.len());
+        for (kernel, output) in kernels.iter().zip(outputs) {
+            let mut expected = vec![0f32; output.len()];
+            convolve(&input, kernel, &mut expected);
+            assert_eq!(output, expected);
+            assert_eq!(output.len(), input.len() + kernel.last().unwrap().0);
+        }
+    }
+
+    #[test]
+    fn processors_are_interchangeable() {
+        let input: Vec<f32> = (0..2000).map(|n| (n as f32 * 0.1).sin()).collect();
+        let kernel = vec![(0, 0.5), (3, -1.), (20, 0.25), (64, 1.)];
+
+        let mut processors: Vec<Box<dyn VelvetProcessor>> = vec![
+            Box::new(RingConvolver::new(kernel.clone(), None)),
+            Box::new(crate::reverb::VelvetReverb::new(kernel).with_allpass(false)),
+        ];
+        let outputs: Vec<Vec<f32>> = processors
+            .iter_mut()
+            .map(|processor| {
+                let mut output = vec![0f32; input.len()];
+                for (x, y) in input.chunks(128).zip(output.chunks_mut(128)) {
+                    processor.process(x, y);
+                }
+                output
+            })
+            .collect();
+
+        for (a, b) in outputs[0].iter().zip(outputs[1].iter()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn convolve_mix_extremes() {
+        let input: Vec<f32> = (0..100).map(|n| (n as f32 * 0.3).sin()).collect();
+        let kernel = vec![(2, 1.), (5, -0.5), (17, 0.25)];
+
+        let mut dry = vec![1f32; 120];
+        convolve_mix(&input, &kernel, 0., &mut dry);
+        assert_eq!(&dry[..100], &input[..]);
+        assert!(dry[100..].iter().all(|x| *x == 0.));
+
+        let mut wet = vec![0f32; 120];
+        convolve_mix(&input, &kernel, 1., &mut wet);
+        let mut expected = vec![0f32; 120];
+        convolve(&input, &kernel, &mut expected);
+        assert_eq!(wet, expected);
+
+        let mut half = vec![0f32; 120];
+        convolve_mix(&input, &kernel, 0.5, &mut half);
+        for n in 0..120 {
+            let dry = input.get(n).cloned().unwrap_or(0.);
+            assert!((half[n] - 0.5 * (dry + expected[n])).abs() < 1e-6);
+        }
+    }
+}
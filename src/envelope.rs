@@ -0,0 +1,85 @@
+//! Amplitude envelopes for shaping velvet noise into notes
+
+/// Linear attack-decay-sustain-release envelope. Times are in seconds.
+#[derive(Clone, Debug)]
+pub struct Adsr {
+    attack: usize,
+    decay: usize,
+    sustain: f32,
+    release: usize,
+}
+
+impl Adsr {
+    /// sustain is the level held while the gate is open, between 0 and 1
+    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32, sample_rate: usize) -> Self {
+        let samples = |seconds: f32| (seconds * sample_rate as f32).round() as usize;
+        Self {
+            attack: samples(attack),
+            decay: samples(decay),
+            sustain,
+            release: samples(release),
+        }
+    }
+
+    /// Envelope for a note held for `gate_samples`, followed by the release.
+    /// A gate shorter than the attack and decay releases from wherever the envelope got to.
+    pub fn render(&self, gate_samples: usize) -> Vec<f32> {
+        let held = (0..gate_samples).map(|n| {
+            if n < self.attack {
+                n as f32 / self.attack as f32
+            } else if n < self.attack + self.decay {
+                let progress = (n - self.attack) as f32 / self.decay as f32;
+                1. + (self.sustain - 1.) * progress
+            } else {
+                self.sustain
+            }
+        });
+        let mut envelope: Vec<f32> = held.collect();
+
+        let release_from = envelope.last().cloned().unwrap_or(0.);
+        envelope.extend(
+            (1..=self.release).map(|n| release_from * (1. - n as f32 / self.release as f32)),
+        );
+        envelope
+    }
+
+    /// Multiply a signal by the envelope of a note held for `gate_samples`. The signal ends
+    /// with the release.
+    pub fn apply<I: Iterator<Item = f32>>(
+        &self,
+        signal: I,
+        gate_samples: usize,
+    ) -> impl Iterator<Item = f32> {
+        signal
+            .zip(self.render(gate_samples))
+            .map(|(x, gain)| x * gain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adsr_shape() {
+        let adsr = Adsr::new(0.01, 0.02, 0.5, 0.1, 1000);
+        let envelope = adsr.render(100);
+        assert_eq!(envelope.len(), 200);
+        assert_eq!(envelope[0], 0.);
+        assert_eq!(envelope[5], 0.5);
+        assert_eq!(envelope[10], 1.);
+        assert_eq!(envelope[20], 0.75);
+        assert!(envelope[30..100].iter().all(|x| *x == 0.5));
+        assert!(envelope[100..].windows(2).all(|pair| pair[1] < pair[0]));
+        assert_eq!(envelope[199], 0.);
+    }
+
+    #[test]
+    fn short_gate_releases_from_attack() {
+        let adsr = Adsr::new(0.01, 0.02, 0.5, 0.01, 1000);
+        let envelope = adsr.render(5);
+        assert_eq!(envelope.len(), 15);
+        assert_eq!(envelope[4], 0.4);
+        assert!((envelope[9] - 0.2).abs() < 1e-6);
+    }
+}
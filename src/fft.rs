@@ -0,0 +1,144 @@
+//! Minimal radix-2 FFT for the spectral analysis and design helpers
+
+use std::f32::consts::PI;
+use std::ops::{Add, Mul, Sub};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub fn new(re: f32, im: f32) -> Self {
+        Self { re, im }
+    }
+
+    pub fn from_polar(magnitude: f32, phase: f32) -> Self {
+        Self::new(magnitude * phase.cos(), magnitude * phase.sin())
+    }
+
+    pub fn norm(self) -> f32 {
+        self.re.hypot(self.im)
+    }
+
+    pub fn arg(self) -> f32 {
+        self.im.atan2(self.re)
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Self::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+/// In-place iterative FFT. The length must be a power of two.
+/// The inverse transform is scaled by 1/N.
+pub(crate) fn fft(buffer: &mut [Complex], inverse: bool) {
+    let n = buffer.len();
+    assert!(n.is_power_of_two(), "FFT size must be a power of two");
+
+    // bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            buffer.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1. } else { -1. };
+    let mut length = 2;
+    while length <= n {
+        let step = Complex::from_polar(1., sign * 2. * PI / length as f32);
+        for start in (0..n).step_by(length) {
+            let mut w = Complex::new(1., 0.);
+            for k in 0..length / 2 {
+                let even = buffer[start + k];
+                let odd = buffer[start + k + length / 2] * w;
+                buffer[start + k] = even + odd;
+                buffer[start + k + length / 2] = even - odd;
+                w = w * step;
+            }
+        }
+        length <<= 1;
+    }
+
+    if inverse {
+        for x in buffer.iter_mut() {
+            x.re /= n as f32;
+            x.im /= n as f32;
+        }
+    }
+}
+
+/// Spectrum of a sparse kernel, zero padded or truncated to fft_size
+pub(crate) fn kernel_spectrum(kernel: &[(usize, f32)], fft_size: usize) -> Vec<Complex> {
+    let mut buffer = vec![Complex::default(); fft_size];
+    for (idx, coeff) in kernel.iter().filter(|(idx, _coeff)| *idx < fft_size) {
+        buffer[*idx].re += coeff;
+    }
+    fft(&mut buffer, false);
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn impulse_has_flat_spectrum() {
+        let spectrum = kernel_spectrum(&[(0, 1.)], 16);
+        assert!(spectrum.iter().all(|x| (x.norm() - 1.).abs() < 1e-6));
+    }
+
+    #[test]
+    fn round_trip() {
+        let input: Vec<Complex> = (0..64)
+            .map(|n| Complex::new((n as f32 * 0.3).sin(), 0.))
+            .collect();
+        let mut buffer = input.clone();
+        fft(&mut buffer, false);
+        fft(&mut buffer, true);
+        for (a, b) in input.iter().zip(buffer.iter()) {
+            assert!((a.re - b.re).abs() < 1e-5 && b.im.abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn tone_lands_in_its_bin() {
+        let mut buffer: Vec<Complex> = (0..64)
+            .map(|n| Complex::new((2. * PI * 4. * n as f32 / 64.).cos(), 0.))
+            .collect();
+        fft(&mut buffer, false);
+        assert!((buffer[4].norm() - 32.).abs() < 1e-3);
+        assert!(buffer[5].norm() < 1e-3);
+    }
+}
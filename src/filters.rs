@@ -0,0 +1,238 @@
+//! Filters for colouring velvet noise
+
+use std::f32::consts::PI;
+
+/// A filter processing one sample at a time, as run by `Filtered`
+pub trait Filter {
+    fn process(&mut self, x: f32) -> f32;
+}
+
+/// Second order IIR filter in transposed direct form II.
+/// Coefficient formulas from the RBJ Audio EQ Cookbook.
+#[derive(Clone, Debug)]
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    /// Coefficients are normalised by a0
+    fn from_coefficients(b: [f32; 3], a: [f32; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            z1: 0.,
+            z2: 0.,
+        }
+    }
+
+    /// cos(w0) and alpha for the given cutoff and Q
+    fn prewarp(cutoff: f32, q: f32, sample_rate: f32) -> (f32, f32) {
+        let w0 = 2. * PI * cutoff / sample_rate;
+        (w0.cos(), w0.sin() / (2. * q))
+    }
+
+    pub fn lowpass(cutoff: f32, q: f32, sample_rate: f32) -> Self {
+        let (cos_w0, alpha) = Biquad::prewarp(cutoff, q, sample_rate);
+        Biquad::from_coefficients(
+            [(1. - cos_w0) / 2., 1. - cos_w0, (1. - cos_w0) / 2.],
+            [1. + alpha, -2. * cos_w0, 1. - alpha],
+        )
+    }
+
+    pub fn highpass(cutoff: f32, q: f32, sample_rate: f32) -> Self {
+        let (cos_w0, alpha) = Biquad::prewarp(cutoff, q, sample_rate);
+        Biquad::from_coefficients(
+            [(1. + cos_w0) / 2., -(1. + cos_w0), (1. + cos_w0) / 2.],
+            [1. + alpha, -2. * cos_w0, 1. - alpha],
+        )
+    }
+
+    /// Band pass with 0 dB gain at the centre frequency
+    pub fn bandpass(centre: f32, q: f32, sample_rate: f32) -> Self {
+        let (cos_w0, alpha) = Biquad::prewarp(centre, q, sample_rate);
+        Biquad::from_coefficients([alpha, 0., -alpha], [1. + alpha, -2. * cos_w0, 1. - alpha])
+    }
+
+    /// First order differencing filter, `y[n] = (x[n] - x[n - 1]) / 2`.
+    /// Rises 6 dB per octave to unity gain at Nyquist, giving violet ("blue") noise from white.
+    pub fn violet() -> Self {
+        Biquad::from_coefficients([0.5, -0.5, 0.], [1., 0., 0.])
+    }
+
+    /// Filter a single sample
+    pub fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+
+    /// Run a signal, e.g. `VelvetNoise`, through the filter
+    pub fn apply<I: Iterator<Item = f32>>(self, signal: I) -> Filtered<I> {
+        Filtered {
+            signal,
+            filter: self,
+        }
+    }
+}
+
+impl Filter for Biquad {
+    fn process(&mut self, x: f32) -> f32 {
+        Biquad::process(self, x)
+    }
+}
+
+/// Pink (-3 dB per octave) filter for white noise, using Paul Kellet's refined method.
+/// The coefficients are designed for 44.1 kHz; the slope is approximate at other rates.
+/// Gain is roughly unity in the mid range.
+#[derive(Clone, Debug, Default)]
+pub struct Pink {
+    state: [f32; 7],
+}
+
+impl Pink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Filter a single sample
+    pub fn process(&mut self, x: f32) -> f32 {
+        let b = &mut self.state;
+        b[0] = 0.99886 * b[0] + x * 0.0555179;
+        b[1] = 0.99332 * b[1] + x * 0.0750759;
+        b[2] = 0.96900 * b[2] + x * 0.153_852;
+        b[3] = 0.86650 * b[3] + x * 0.3104856;
+        b[4] = 0.55000 * b[4] + x * 0.5329522;
+        b[5] = -0.7616 * b[5] - x * 0.0168980;
+        let pink = b.iter().sum::<f32>() + x * 0.5362;
+        b[6] = x * 0.115926;
+        pink * 0.25
+    }
+
+    /// Run a signal, e.g. `VelvetNoise`, through the filter
+    pub fn apply<I: Iterator<Item = f32>>(self, signal: I) -> Filtered<I, Pink> {
+        Filtered {
+            signal,
+            filter: self,
+        }
+    }
+}
+
+impl Filter for Pink {
+    fn process(&mut self, x: f32) -> f32 {
+        Pink::process(self, x)
+    }
+}
+
+/// Signal passed through a `Biquad` or other `Filter`
+#[derive(Clone, Debug)]
+pub struct Filtered<I, F = Biquad> {
+    signal: I,
+    filter: F,
+}
+
+impl<I: Iterator<Item = f32>, F: Filter> Iterator for Filtered<I, F> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.signal.next().map(|x| self.filter.process(x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fft::{fft, Complex};
+    use crate::{blue_velvet, OriginalVelvetNoise};
+    use more_asserts::*;
+
+    fn tone(frequency: f32, sample_rate: f32) -> impl Iterator<Item = f32> {
+        (0..).map(move |n| (2. * PI * frequency * n as f32 / sample_rate).sin())
+    }
+
+    /// RMS after the filter has settled
+    fn filtered_rms(filter: Biquad, signal: impl Iterator<Item = f32>) -> f32 {
+        rms_after_settling(filter.apply(signal))
+    }
+
+    fn rms_after_settling(filtered: impl Iterator<Item = f32>) -> f32 {
+        let samples: Vec<f32> = filtered.skip(4410).take(44100).collect();
+        (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn lowpass_attenuates_high_tones() {
+        let sample_rate = 44100.;
+        let lowpass = Biquad::lowpass(1000., 0.707, sample_rate);
+        let low = filtered_rms(lowpass.clone(), tone(100., sample_rate));
+        let high = filtered_rms(lowpass, tone(10000., sample_rate));
+
+        assert_gt!(low, 0.69);
+        assert_lt!(high, 0.01);
+    }
+
+    #[test]
+    fn highpass_and_bandpass_attenuate_low_tones() {
+        let sample_rate = 44100.;
+        let highpass = Biquad::highpass(1000., 0.707, sample_rate);
+        assert_lt!(filtered_rms(highpass.clone(), tone(50., sample_rate)), 0.01);
+        assert_gt!(filtered_rms(highpass, tone(10000., sample_rate)), 0.69);
+
+        let bandpass = Biquad::bandpass(1000., 2., sample_rate);
+        assert_lt!(filtered_rms(bandpass.clone(), tone(50., sample_rate)), 0.02);
+        assert_gt!(filtered_rms(bandpass, tone(1000., sample_rate)), 0.69);
+    }
+
+    #[test]
+    fn filter_velvet_noise() {
+        let noise = OriginalVelvetNoise::new(2000., 44100., None);
+        let filtered = Biquad::lowpass(1000., 0.707, 44100.).apply(noise);
+        for sample in filtered.take(44100) {
+            assert!(sample.is_finite());
+            assert_lt!(sample.abs(), 2.);
+        }
+    }
+
+    #[test]
+    fn pink_filter_falls_3db_per_octave() {
+        let sample_rate = 44100.;
+        let rms = |frequency| rms_after_settling(Pink::new().apply(tone(frequency, sample_rate)));
+        // four octaves apart, so 12 dB
+        let ratio_db = 20. * (rms(1600.) / rms(100.)).log10();
+        assert_lt!((ratio_db + 12.).abs(), 1.);
+        assert_lt!((rms(1000.) - 0.707).abs(), 0.2);
+    }
+
+    #[test]
+    fn violet_filter_passes_high_tones() {
+        let sample_rate = 44100.;
+        assert_lt!(filtered_rms(Biquad::violet(), tone(50., sample_rate)), 0.01);
+        assert_gt!(
+            filtered_rms(Biquad::violet(), tone(20000., sample_rate)),
+            0.69
+        );
+    }
+
+    #[test]
+    fn blue_velvet_is_bright() {
+        let mut spectrum: Vec<Complex> = blue_velvet(2000., 44100.)
+            .take(4096)
+            .map(|x| Complex::new(x, 0.))
+            .collect();
+        fft(&mut spectrum, false);
+
+        let band_energy = |bins: &[Complex]| bins.iter().map(|c| c.norm().powi(2)).sum::<f32>();
+        let low = band_energy(&spectrum[1..512]);
+        let high = band_energy(&spectrum[1536..2048]);
+        assert_gt!(high, 4. * low);
+    }
+}
@@ -0,0 +1,132 @@
+//! Granular textures made of short velvet grains
+
+use crate::convolve::convolve;
+use crate::OVNImpulseLocations;
+use std::f32::consts::PI;
+use std::iter::Peekable;
+
+/// Hann window of `length` samples, e.g. for shaping grains
+pub fn hann_window(length: usize) -> Vec<f32> {
+    (0..length)
+        .map(|n| 0.5 - 0.5 * (2. * PI * n as f32 / length as f32).cos())
+        .collect()
+}
+
+/// A short velvet kernel convolved with a window, ready to be triggered
+#[derive(Clone, Debug)]
+pub struct Grain {
+    samples: Vec<f32>,
+}
+
+impl Grain {
+    /// The grain is the full convolution of `kernel` with `window`
+    pub fn new(kernel: &[(usize, f32)], window: &[f32]) -> Self {
+        let tail = kernel.iter().map(|(idx, _coeff)| *idx).max().unwrap_or(0);
+        let mut samples = vec![0.; window.len() + tail];
+        convolve(window, kernel, &mut samples);
+        Self { samples }
+    }
+
+    /// Rendered grain
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+}
+
+/// Triggers a grain at every OVN impulse location, summing overlapping grains
+#[derive(Clone, Debug)]
+pub struct GranularVelvet {
+    grain: Grain,
+    onsets: Peekable<OVNImpulseLocations>,
+    position: usize,
+    pending: Vec<f32>,
+}
+
+impl GranularVelvet {
+    /// density is grains per second
+    pub fn new(grain: Grain, density: usize, sample_rate: usize) -> Self {
+        Self::from_locations(grain, OVNImpulseLocations::new(density, sample_rate))
+    }
+
+    /// Reproducible onsets for a given seed
+    pub fn from_seed(grain: Grain, density: usize, sample_rate: usize, seed: u64) -> Self {
+        Self::from_locations(
+            grain,
+            OVNImpulseLocations::from_seed(density, sample_rate, seed),
+        )
+    }
+
+    fn from_locations(grain: Grain, onsets: OVNImpulseLocations) -> Self {
+        Self {
+            grain,
+            onsets: onsets.peekable(),
+            position: 0,
+            pending: vec![],
+        }
+    }
+
+    /// The next `n_samples` of output, continuing from previous calls.
+    /// Grains still sounding at the end of the block carry over into the next one.
+    pub fn render(&mut self, n_samples: usize) -> Vec<f32> {
+        let end = self.position + n_samples;
+        if self.pending.len() < n_samples {
+            self.pending.resize(n_samples, 0.);
+        }
+
+        let grain = self.grain.samples();
+        while let Some(onset) = self.onsets.next_if(|onset| *onset < end) {
+            let offset = onset - self.position;
+            if self.pending.len() < offset + grain.len() {
+                self.pending.resize(offset + grain.len(), 0.);
+            }
+            for (out, x) in self.pending[offset..].iter_mut().zip(grain) {
+                *out += x;
+            }
+        }
+
+        self.position = end;
+        self.pending.drain(..n_samples).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grain_onsets_follow_ovn() {
+        let grain = Grain::new(&[(0, 1.)], &[1.]);
+        let mut granular = GranularVelvet::from_seed(grain, 100, 1000, 6);
+        let output: Vec<f32> = (0..4).flat_map(|_| granular.render(250)).collect();
+
+        let onsets: Vec<usize> = output
+            .iter()
+            .enumerate()
+            .filter(|(_n, x)| **x != 0.)
+            .map(|(n, _x)| n)
+            .collect();
+        let expected: Vec<usize> = OVNImpulseLocations::from_seed(100, 1000, 6)
+            .take_while(|idx| *idx < 1000)
+            .collect();
+        assert_eq!(onsets, expected);
+    }
+
+    #[test]
+    fn overlapping_grains_sum() {
+        let grain = Grain::new(&[(0, 1.), (7, -1.), (30, 1.)], &hann_window(64));
+        assert_eq!(grain.samples().len(), 94);
+
+        let mut granular = GranularVelvet::from_seed(grain.clone(), 100, 1000, 2);
+        let output: Vec<f32> = (0..10).flat_map(|_| granular.render(100)).collect();
+
+        let mut expected = vec![0f32; 1000 + grain.samples().len()];
+        for onset in OVNImpulseLocations::from_seed(100, 1000, 2).take_while(|idx| *idx < 1000) {
+            for (out, x) in expected[onset..].iter_mut().zip(grain.samples()) {
+                *out += x;
+            }
+        }
+        for (a, b) in output.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,935 @@
+//! Utilities for working with rendered velvet kernels, i.e. sparse `(index, coefficient)` lists.
+
+use crate::fft::{fft, kernel_spectrum, Complex};
+use crate::{Choice, OVNImpulseLocations, VelvetError, VelvetNoiseKernel, VelvetRng};
+use rand::{Rng, SeedableRng};
+use std::f32::consts::PI;
+use std::fmt::Debug;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, MulAssign, Neg, Sub};
+
+/// Kernel coefficient type. Implemented for `f32`, the default throughout the crate, and `f64`.
+pub trait Coefficient:
+    Copy
+    + Debug
+    + PartialOrd
+    + Add<Output = Self>
+    + AddAssign
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + MulAssign
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + Sum
+{
+    const ZERO: Self;
+    const ONE: Self;
+
+    fn from_f32(x: f32) -> Self;
+    fn abs(self) -> Self;
+    fn sqrt(self) -> Self;
+}
+
+impl Coefficient for f32 {
+    const ZERO: Self = 0.;
+    const ONE: Self = 1.;
+
+    fn from_f32(x: f32) -> Self {
+        x
+    }
+
+    fn abs(self) -> Self {
+        f32::abs(self)
+    }
+
+    fn sqrt(self) -> Self {
+        f32::sqrt(self)
+    }
+}
+
+impl Coefficient for f64 {
+    const ZERO: Self = 0.;
+    const ONE: Self = 1.;
+
+    fn from_f32(x: f32) -> Self {
+        x as f64
+    }
+
+    fn abs(self) -> Self {
+        f64::abs(self)
+    }
+
+    fn sqrt(self) -> Self {
+        f64::sqrt(self)
+    }
+}
+
+/// Total number of taps across several kernels, before any collisions are merged
+pub fn combined_len<S: Coefficient>(kernels: &[Vec<(usize, S)>]) -> usize {
+    kernels.iter().map(|kernel| kernel.len()).sum()
+}
+
+/// Combine several kernels into one sorted by index.
+/// Coefficients of impulses sharing an index are summed.
+pub fn combine_kernels<S: Coefficient>(kernels: &[Vec<(usize, S)>]) -> Vec<(usize, S)> {
+    let mut combined: Vec<(usize, S)> = Vec::with_capacity(combined_len(kernels));
+    combined.extend(kernels.iter().flatten().cloned());
+    combined.sort_by_key(|(idx, _coeff)| *idx);
+    combined.dedup_by(|(idx, coeff), (prev_idx, prev_coeff)| {
+        let collision = idx == prev_idx;
+        if collision {
+            *prev_coeff += *coeff;
+        }
+        collision
+    });
+    combined
+}
+
+/// Partition a kernel into bands `borders[i]..borders[i + 1]`, the inverse of combining
+/// per-stage kernels. Taps outside the outer borders are dropped.
+pub fn split_kernel_by_borders<S: Coefficient>(
+    kernel: &[(usize, S)],
+    borders: &[usize],
+) -> Vec<Vec<(usize, S)>> {
+    borders
+        .windows(2)
+        .map(|band| {
+            kernel
+                .iter()
+                .filter(|(idx, _coeff)| *idx >= band[0] && *idx < band[1])
+                .cloned()
+                .collect()
+        })
+        .collect()
+}
+
+/// Crossfade between two kernels, scaling `a` by `1 - mix` and `b` by `mix`.
+/// A kernel whose weight is zero is left out entirely, so `mix = 0` gives `a` and `mix = 1`
+/// gives `b`.
+pub fn crossfade_kernels<S: Coefficient>(
+    a: &[(usize, S)],
+    b: &[(usize, S)],
+    mix: S,
+) -> Vec<(usize, S)> {
+    let scaled = |kernel: &[(usize, S)], gain: S| -> Vec<(usize, S)> {
+        if gain == S::ZERO {
+            return vec![];
+        }
+        kernel
+            .iter()
+            .map(|(idx, coeff)| (*idx, *coeff * gain))
+            .collect()
+    };
+    combine_kernels(&[scaled(a, S::ONE - mix), scaled(b, mix)])
+}
+
+/// Dense impulse response of `length` samples. Impulses beyond the end are dropped.
+pub fn densify<S: Coefficient>(kernel: &[(usize, S)], length: usize) -> Vec<S> {
+    let mut ir = vec![S::ZERO; length];
+    for (idx, coeff) in kernel.iter().filter(|(idx, _coeff)| *idx < length) {
+        ir[*idx] += *coeff;
+    }
+    ir
+}
+
+/// Strip leading and trailing samples whose magnitude is at most `threshold` from a dense IR.
+/// Returns the number of samples removed from the start along with the trimmed IR.
+pub fn trim_silence<S: Coefficient>(ir: &[S], threshold: S) -> (usize, &[S]) {
+    let audible = |x: &S| x.abs() > threshold;
+    match ir.iter().position(audible) {
+        Some(start) => {
+            let end = ir.iter().rposition(audible).unwrap() + 1;
+            (start, &ir[start..end])
+        }
+        None => (ir.len(), &[]),
+    }
+}
+
+/// Sparse kernel holding the non-zero samples of a dense impulse response
+pub fn sparsify<S: Coefficient>(ir: &[S]) -> Vec<(usize, S)> {
+    ir.iter()
+        .enumerate()
+        .filter(|(_idx, x)| **x != S::ZERO)
+        .map(|(idx, x)| (idx, *x))
+        .collect()
+}
+
+/// Sum of squared coefficients
+pub fn energy<S: Coefficient>(kernel: &[(usize, S)]) -> S {
+    kernel.iter().map(|(_idx, coeff)| *coeff * *coeff).sum()
+}
+
+/// Scale coefficients in place so the kernel has unit energy.
+/// A kernel with no energy is left untouched.
+pub fn normalize_energy<S: Coefficient>(kernel: &mut [(usize, S)]) {
+    let energy = energy(kernel);
+    if energy == S::ZERO {
+        return;
+    }
+    let gain = S::ONE / energy.sqrt();
+    for (_idx, coeff) in kernel.iter_mut() {
+        *coeff *= gain;
+    }
+}
+
+/// DC component of a kernel, the sum of its coefficients
+pub fn kernel_dc<S: Coefficient>(kernel: &[(usize, S)]) -> S {
+    kernel.iter().map(|(_idx, coeff)| *coeff).sum()
+}
+
+/// Subtract the mean coefficient from every tap in place so the kernel has no DC, avoiding
+/// thumps from skewed kernels. Impulse locations are unchanged.
+pub fn remove_dc<S: Coefficient>(kernel: &mut [(usize, S)]) {
+    if kernel.is_empty() {
+        return;
+    }
+    let mean = kernel_dc(kernel) / S::from_f32(kernel.len() as f32);
+    for (_idx, coeff) in kernel.iter_mut() {
+        *coeff = *coeff - mean;
+    }
+}
+
+/// Linearly fade in the taps of the band `band_start..band_end` over its first `fade_samples`
+/// and fade them out over its last `fade_samples`, smoothing the seams between reverb stages.
+/// Taps outside the band are untouched.
+pub fn taper_band_edges<S: Coefficient>(
+    kernel: &mut [(usize, S)],
+    band_start: usize,
+    band_end: usize,
+    fade_samples: usize,
+) {
+    if fade_samples == 0 {
+        return;
+    }
+    let fade = S::from_f32(fade_samples as f32);
+    for (idx, coeff) in kernel
+        .iter_mut()
+        .filter(|(idx, _coeff)| *idx >= band_start && *idx < band_end)
+    {
+        let from_edge = (*idx - band_start).min(band_end - 1 - *idx);
+        if from_edge < fade_samples {
+            *coeff *= S::from_f32(from_edge as f32) / fade;
+        }
+    }
+}
+
+/// Limit every coefficient to `[-max_abs, max_abs]` in place, e.g. after DC removal or
+/// spectral shaping pushed some beyond unit magnitude
+pub fn clamp_coefficients<S: Coefficient>(kernel: &mut [(usize, S)], max_abs: S) {
+    for (_idx, coeff) in kernel.iter_mut() {
+        if *coeff > max_abs {
+            *coeff = max_abs;
+        } else if *coeff < -max_abs {
+            *coeff = -max_abs;
+        }
+    }
+}
+
+/// Replace each impulse with a copy of `shape` centred on it, softening the clicks of single
+/// sample impulses. Taps that would land before index 0 are dropped. Overlapping taps are not
+/// merged, use `combine_kernels` for that.
+pub fn shaped_kernel<S: Coefficient>(kernel: &[(usize, S)], shape: &[S]) -> Vec<(usize, S)> {
+    let centre = shape.len() / 2;
+    kernel
+        .iter()
+        .flat_map(|(idx, coeff)| {
+            shape.iter().enumerate().filter_map(move |(j, x)| {
+                (idx + j)
+                    .checked_sub(centre)
+                    .map(|shaped_idx| (shaped_idx, *coeff * *x))
+            })
+        })
+        .collect()
+}
+
+/// Half-width in samples of the windowed sinc used by `render_fractional`
+pub const FRACTIONAL_HALF_WIDTH: usize = 4;
+
+/// Kernel with impulses of `gain` at fractional positions, e.g. ARN locations before rounding.
+/// Each impulse becomes a Hann-windowed sinc over the `2 * FRACTIONAL_HALF_WIDTH` nearest
+/// samples, a fractional delay, so most of its energy falls on the two nearest samples in
+/// proportion to its distance from each. Whole-sample positions give a single tap. Taps that
+/// would land before index 0 are dropped and overlapping taps are summed.
+pub fn render_fractional(positions: &[f32], gain: f32) -> Vec<(usize, f32)> {
+    let half_width = FRACTIONAL_HALF_WIDTH as isize;
+    let taps: Vec<(usize, f32)> = positions
+        .iter()
+        .flat_map(|position| {
+            let base = position.floor();
+            let fraction = position - base;
+            (1 - half_width..=half_width).filter_map(move |k| {
+                let idx = base as isize + k;
+                let x = k as f32 - fraction;
+                let weight = if x == 0. {
+                    1.
+                } else {
+                    let window = 0.5 + 0.5 * (PI * x / half_width as f32).cos();
+                    window * (PI * x).sin() / (PI * x)
+                };
+                if idx < 0 || weight.abs() < 1e-6 {
+                    None
+                } else {
+                    Some((idx as usize, weight * gain))
+                }
+            })
+        })
+        .collect();
+    combine_kernels(&[taps])
+}
+
+/// Merge impulses closer than `min_gap` samples to the preceding kept impulse, summing their
+/// coefficients at the earlier index. The kernel is sorted by index first.
+pub fn dedup_close<S: Coefficient>(kernel: &mut Vec<(usize, S)>, min_gap: usize) {
+    kernel.sort_by_key(|(idx, _coeff)| *idx);
+    kernel.dedup_by(|(idx, coeff), (prev_idx, prev_coeff)| {
+        let close = *idx - *prev_idx < min_gap;
+        if close {
+            *prev_coeff += *coeff;
+        }
+        close
+    });
+}
+
+/// Check that every tap index is at most `max_allowed_index` and every coefficient is finite,
+/// e.g. before handing an imported kernel to a real-time convolver.
+pub fn validate_kernel(
+    kernel: &[(usize, f32)],
+    max_allowed_index: usize,
+) -> Result<(), VelvetError> {
+    for (idx, coeff) in kernel.iter() {
+        if *idx > max_allowed_index {
+            return Err(VelvetError::IndexOutOfRange {
+                index: *idx,
+                max_allowed: max_allowed_index,
+            });
+        }
+        if !coeff.is_finite() {
+            return Err(VelvetError::NonFiniteCoefficient { index: *idx });
+        }
+    }
+    Ok(())
+}
+
+/// As `validate_kernel`, additionally checking that indices are in ascending order
+pub fn validate_sorted_kernel(
+    kernel: &[(usize, f32)],
+    max_allowed_index: usize,
+) -> Result<(), VelvetError> {
+    validate_kernel(kernel, max_allowed_index)?;
+    match kernel.windows(2).find(|pair| pair[1].0 < pair[0].0) {
+        Some(pair) => Err(VelvetError::Unsorted { index: pair[1].0 }),
+        None => Ok(()),
+    }
+}
+
+/// Stable 64 bit key for the parameters of a rendered kernel, for caching kernels by parameter.
+/// This is an FNV-1a hash of the parameters and of the `VelvetRng` in use, so it does not change
+/// between runs, platforms or compiler versions.
+pub fn kernel_fingerprint(density: usize, sample_rate: usize, length: usize, seed: u64) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+    let rng_tag = if cfg!(feature = "rand_pcg") { 1 } else { 0 };
+    [
+        density as u64,
+        sample_rate as u64,
+        length as u64,
+        seed,
+        rng_tag,
+    ]
+    .iter()
+    .flat_map(|x| x.to_le_bytes())
+    .fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Kernel as `(time_seconds, positive)` events, e.g. for sequencing or drawing the pattern in
+/// a DAW. Takes the kernel rather than bare locations so each event keeps its sign.
+pub fn locations_to_events(kernel: &[(usize, f32)], sample_rate: usize) -> Vec<(f64, bool)> {
+    kernel
+        .iter()
+        .map(|(idx, coeff)| (*idx as f64 / sample_rate as f64, *coeff > 0.))
+        .collect()
+}
+
+/// C source defining a kernel as a tap count and parallel `const` arrays of indices and
+/// coefficients, named `<name>_len`, `<name>_indices` and `<name>_coefficients`, e.g. for
+/// embedded firmware. The arrays are omitted for an empty kernel, as C has no empty arrays.
+pub fn kernel_to_c_array(kernel: &[(usize, f32)], name: &str) -> String {
+    let len = kernel.len();
+    let mut source = format!("const unsigned long {}_len = {};\n", name, len);
+    if len == 0 {
+        return source;
+    }
+
+    let indices: Vec<String> = kernel.iter().map(|(idx, _coeff)| idx.to_string()).collect();
+    let coefficients: Vec<String> = kernel
+        .iter()
+        .map(|(_idx, coeff)| format!("{:?}f", coeff))
+        .collect();
+    source += &format!(
+        "const unsigned long {}_indices[{}] = {{{}}};\n",
+        name,
+        len,
+        indices.join(", ")
+    );
+    source += &format!(
+        "const float {}_coefficients[{}] = {{{}}};\n",
+        name,
+        len,
+        coefficients.join(", ")
+    );
+    source
+}
+
+/// Two kernels of `length` samples at different densities, both normalised to unit energy so
+/// crossfading between them doesn't jump in loudness
+#[allow(clippy::type_complexity)]
+pub fn matched_pair(
+    density_a: usize,
+    density_b: usize,
+    sample_rate: usize,
+    length: usize,
+    seed: u64,
+) -> (Vec<(usize, f32)>, Vec<(usize, f32)>) {
+    let render = |density, seed: u64| {
+        let mut kernel = VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(density, sample_rate, seed),
+            Choice::from_seed(0.5, seed.wrapping_add(1)),
+        )
+        .render(0, length, 1.);
+        normalize_energy(&mut kernel);
+        kernel
+    };
+    (
+        render(density_a, seed),
+        render(density_b, seed.wrapping_add(2)),
+    )
+}
+
+/// Velvet kernel for a seamless loop of `loop_len` samples.
+/// Impulses are rendered over whole windows, so the last window may run past the end of the
+/// loop; its impulse wraps around to the start instead of being dropped. Indices are taken
+/// modulo `loop_len` and coefficients of colliding impulses summed. `density` must be non-zero
+/// and at most `sample_rate`.
+pub fn render_looping(
+    density: usize,
+    sample_rate: usize,
+    loop_len: usize,
+    gain: f32,
+    seed: u64,
+) -> Vec<(usize, f32)> {
+    assert!(
+        density > 0 && density <= sample_rate,
+        "density must be non-zero and not exceed sample_rate"
+    );
+    if loop_len == 0 {
+        return vec![];
+    }
+    let td = sample_rate / density;
+    let span = loop_len.div_ceil(td) * td;
+    let kernel = VelvetNoiseKernel::new(
+        OVNImpulseLocations::from_seed(density, sample_rate, seed),
+        Choice::from_seed(0.5, seed.wrapping_add(1)),
+    )
+    .render(0, span, gain)
+    .into_iter()
+    .map(|(idx, coeff)| (idx % loop_len, coeff))
+    .collect();
+    combine_kernels(&[kernel])
+}
+
+/// Number of alternating projections used by `shape_to_spectrum`
+const SPECTRUM_SHAPING_ITERATIONS: usize = 100;
+
+/// Choose coefficients for fixed impulse locations so the kernel's magnitude spectrum
+/// approximates `target_mag`, which holds the `fft_size / 2 + 1` bins from DC to Nyquist.
+///
+/// Alternates between imposing the target magnitude (keeping the current phase) and projecting
+/// back onto kernels that are only non-zero at `locations`, starting from random signs drawn
+/// from `seed`. The result is scaled to best fit the target in a least squares sense. Locations
+/// must be below `fft_size`, which must be a power of two.
+pub fn shape_to_spectrum(
+    locations: &[usize],
+    target_mag: &[f32],
+    fft_size: usize,
+    seed: u64,
+) -> Vec<(usize, f32)> {
+    assert_eq!(
+        target_mag.len(),
+        fft_size / 2 + 1,
+        "one target per bin up to Nyquist"
+    );
+    assert!(locations.iter().all(|idx| *idx < fft_size));
+
+    let mut kernel: Vec<(usize, f32)> = locations
+        .iter()
+        .cloned()
+        .zip(Choice::from_seed(0.5, seed))
+        .collect();
+
+    for _ in 0..SPECTRUM_SHAPING_ITERATIONS {
+        let spectrum = kernel_spectrum(&kernel, fft_size);
+
+        // impose the target magnitude, mirroring for a real signal
+        let mut shaped: Vec<Complex> = spectrum
+            .iter()
+            .enumerate()
+            .map(|(bin, x)| {
+                let mirrored = if bin <= fft_size / 2 {
+                    bin
+                } else {
+                    fft_size - bin
+                };
+                Complex::from_polar(target_mag[mirrored], x.arg())
+            })
+            .collect();
+        fft(&mut shaped, true);
+
+        for (idx, coeff) in kernel.iter_mut() {
+            *coeff = shaped[*idx].re;
+        }
+    }
+
+    // least squares gain between achieved and target magnitudes
+    let achieved = kernel_spectrum(&kernel, fft_size);
+    let (dot, norm) = target_mag
+        .iter()
+        .zip(achieved.iter())
+        .fold((0., 0.), |(dot, norm), (t, x)| {
+            (dot + t * x.norm(), norm + x.norm() * x.norm())
+        });
+    if norm > 0. {
+        for (_idx, coeff) in kernel.iter_mut() {
+            *coeff *= dot / norm;
+        }
+    }
+    kernel
+}
+
+/// Number of candidate moves tried by `whitened_kernel`
+const WHITENING_ITERATIONS: usize = 400;
+
+/// OVN window length in samples of `whitened_kernel`, i.e. 2205 impulses per second at 44.1 kHz
+pub const WHITENED_KERNEL_WINDOW: usize = 20;
+
+/// Spectral flatness over the bins between DC and Nyquist, see
+/// `analysis::spectral_flatness`
+fn flatness(spectrum: &[Complex]) -> f32 {
+    let bins = &spectrum[1..spectrum.len() / 2];
+    let (sum, log_sum) = bins.iter().fold((0., 0.), |(sum, log_sum), x| {
+        let power = x.norm() * x.norm();
+        (sum + power, log_sum + power.max(f32::MIN_POSITIVE).ln())
+    });
+    let n = bins.len() as f32;
+    (log_sum / n).exp() / (sum / n)
+}
+
+/// Classic velvet kernel of `length` samples whose spectrum has been made flatter than usual.
+///
+/// Starting from an OVN kernel with one impulse every `WHITENED_KERNEL_WINDOW` samples,
+/// repeatedly proposes flipping the sign of a random tap or moving it elsewhere in its window,
+/// keeping the change if it increases the spectral flatness. Each proposal updates the spectrum
+/// directly rather than recomputing the FFT. The result is still a valid OVN kernel with the
+/// same taps count. Kernels shorter than 3 samples are too short to whiten and are returned
+/// as they are.
+pub fn whitened_kernel(length: usize, seed: u64) -> Vec<(usize, f32)> {
+    let td = WHITENED_KERNEL_WINDOW;
+    let mut kernel = VelvetNoiseKernel::new(
+        OVNImpulseLocations::from_seed(1, td, seed),
+        Choice::from_seed(0.5, seed.wrapping_add(1)),
+    )
+    .render(0, length, 1.);
+    let fft_size = length.next_power_of_two();
+    // flatness needs at least one bin between DC and Nyquist
+    if kernel.is_empty() || fft_size < 4 {
+        return kernel;
+    }
+
+    let mut spectrum = kernel_spectrum(&kernel, fft_size);
+    let mut candidate = spectrum.clone();
+    let mut best = flatness(&spectrum);
+    let mut rng = VelvetRng::seed_from_u64(seed.wrapping_add(2));
+
+    // unit impulse at idx evaluated at bin
+    let impulse = |idx: usize, bin: usize| {
+        let phase = -2. * PI * ((bin * idx) % fft_size) as f32 / fft_size as f32;
+        Complex::from_polar(1., phase)
+    };
+
+    for _ in 0..WHITENING_ITERATIONS {
+        let tap = rng.gen_range(0, kernel.len());
+        let (idx, coeff) = kernel[tap];
+        let (new_idx, new_coeff) = if rng.gen::<bool>() {
+            (idx, -coeff)
+        } else {
+            let window_start = idx - idx % td;
+            let window_end = (window_start + td).min(length);
+            (rng.gen_range(window_start, window_end), coeff)
+        };
+
+        for (bin, (x, y)) in spectrum.iter().zip(candidate.iter_mut()).enumerate() {
+            *y = *x - impulse(idx, bin) * Complex::new(coeff, 0.)
+                + impulse(new_idx, bin) * Complex::new(new_coeff, 0.);
+        }
+
+        let candidate_flatness = flatness(&candidate);
+        if candidate_flatness > best {
+            best = candidate_flatness;
+            std::mem::swap(&mut spectrum, &mut candidate);
+            kernel[tap] = (new_idx, new_coeff);
+        }
+    }
+    kernel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Choice, OVNImpulseLocations, VelvetError, VelvetNoiseKernel};
+
+    #[test]
+    fn combine_sorts_and_sums_collisions() {
+        let a = vec![(10, 1.), (30, -1.)];
+        let b = vec![(5, 1.), (10, 0.5)];
+        assert_eq!(
+            combine_kernels(&[a, b]),
+            vec![(5, 1.), (10, 1.5), (30, -1.)]
+        );
+    }
+
+    #[test]
+    fn crossfade_endpoints() {
+        let a = vec![(1, 1.), (7, -1.)];
+        let b = vec![(3, -1.), (7, 1.)];
+        assert_eq!(crossfade_kernels(&a, &b, 0.), a);
+        assert_eq!(crossfade_kernels(&a, &b, 1.), b);
+        assert_eq!(
+            crossfade_kernels(&a, &b, 0.5),
+            vec![(1, 0.5), (3, -0.5), (7, 0.)]
+        );
+    }
+
+    #[test]
+    fn densify_places_impulses() {
+        let kernel = vec![(1, 1f32), (3, -0.5), (9, 1.)];
+        assert_eq!(densify(&kernel, 5), vec![0., 1., 0., -0.5, 0.]);
+    }
+
+    #[test]
+    fn sparsify_inverts_densify() {
+        let kernel = vec![(1, 1f32), (3, -0.5), (4, 1.)];
+        assert_eq!(sparsify(&densify(&kernel, 5)), kernel);
+    }
+
+    #[test]
+    fn render_and_normalize_f64() {
+        let mut kernel: Vec<(usize, f64)> =
+            VelvetNoiseKernel::new(OVNImpulseLocations::new(441, 44100), Choice::classic())
+                .render_as(0, 44100, 0.5f64);
+        assert_eq!(kernel.len(), 441);
+        assert!(kernel.iter().all(|(_idx, coeff)| coeff.abs() == 0.5));
+
+        normalize_energy(&mut kernel);
+        assert!((energy(&kernel) - 1.).abs() < 1e-12);
+
+        // an untyped gain still renders f32 coefficients
+        let kernel: Vec<(usize, f32)> =
+            VelvetNoiseKernel::new(OVNImpulseLocations::new(441, 44100), Choice::classic())
+                .render(0, 44100, 0.5);
+        assert_eq!(kernel.len(), 441);
+    }
+
+    #[test]
+    fn shaped_kernel_expands_taps() {
+        let kernel = vec![(0, 1f32), (10, -1.), (20, 0.5)];
+        let shape = [0.25, 0.5, 1., 0.5, 0.25];
+        let shaped = shaped_kernel(&kernel, &shape);
+
+        // the first impulse loses the two taps before index 0
+        assert_eq!(shaped.len(), kernel.len() * shape.len() - 2);
+        assert_eq!(shaped[..3], [(0, 1.), (1, 0.5), (2, 0.25)]);
+        assert_eq!(
+            shaped[8..13],
+            [(18, 0.125), (19, 0.25), (20, 0.5), (21, 0.25), (22, 0.125)]
+        );
+    }
+
+    #[test]
+    fn dedup_close_merges_near_impulses() {
+        let mut kernel = vec![(100, 1f32), (12, 0.5), (10, 1.), (20, -1.)];
+        dedup_close(&mut kernel, 3);
+        assert_eq!(kernel, vec![(10, 1.5), (20, -1.), (100, 1.)]);
+    }
+
+    #[test]
+    fn split_recovers_combined_bands() {
+        let borders = [100, 200, 350, 400];
+        let bands: Vec<Vec<(usize, f32)>> = borders
+            .windows(2)
+            .enumerate()
+            .map(|(i, band)| {
+                VelvetNoiseKernel::new(OVNImpulseLocations::new(4410, 44100), Choice::classic())
+                    .render(band[0], band[1], 1. / (i + 1) as f32)
+            })
+            .collect();
+
+        let combined = combine_kernels(&bands);
+        assert_eq!(split_kernel_by_borders(&combined, &borders), bands);
+    }
+
+    #[test]
+    fn trim_silence_from_both_ends() {
+        let ir = [0f32, 0.001, 0., 0.5, 0., -1., 0.002, 0.];
+        assert_eq!(trim_silence(&ir, 0.01), (3, &ir[3..6]));
+        assert_eq!(trim_silence(&ir, 0.), (1, &ir[1..7]));
+        assert_eq!(trim_silence(&[0f32; 4], 0.), (4, &[][..]));
+    }
+
+    /// Pearson correlation between a kernel's magnitude spectrum and a target
+    fn spectral_correlation(kernel: &[(usize, f32)], target: &[f32], fft_size: usize) -> f32 {
+        let achieved: Vec<f32> = kernel_spectrum(kernel, fft_size)[..target.len()]
+            .iter()
+            .map(|x| x.norm())
+            .collect();
+        let mean = |x: &[f32]| x.iter().sum::<f32>() / x.len() as f32;
+        let (mean_a, mean_t) = (mean(&achieved), mean(target));
+        let covariance: f32 = achieved
+            .iter()
+            .zip(target)
+            .map(|(a, t)| (a - mean_a) * (t - mean_t))
+            .sum();
+        let variance = |x: &[f32], m: f32| x.iter().map(|v| (v - m).powi(2)).sum::<f32>();
+        covariance / (variance(&achieved, mean_a) * variance(target, mean_t)).sqrt()
+    }
+
+    #[test]
+    fn shaped_spectrum_follows_target() {
+        let fft_size = 1024;
+        let locations: Vec<usize> = OVNImpulseLocations::from_seed(1, 4, 11)
+            .take_while(|idx| *idx < fft_size)
+            .collect();
+        // downward tilt
+        let target: Vec<f32> = (0..=fft_size / 2)
+            .map(|bin| 1. / (1. + bin as f32 / 32.))
+            .collect();
+
+        let random = VelvetNoiseKernel::new(locations.iter().cloned(), Choice::from_seed(0.5, 3))
+            .render(0, fft_size, 1.);
+        let shaped = shape_to_spectrum(&locations, &target, fft_size, 5);
+        assert_eq!(shaped.len(), locations.len());
+        assert_eq!(shape_to_spectrum(&locations, &target, fft_size, 5), shaped);
+
+        let random_corr = spectral_correlation(&random, &target, fft_size);
+        let shaped_corr = spectral_correlation(&shaped, &target, fft_size);
+        assert!(shaped_corr > 0.5);
+        assert!(shaped_corr > random_corr + 0.4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn looping_kernel_rejects_excess_density() {
+        render_looping(48000, 44100, 100, 1., 0);
+    }
+
+    #[test]
+    fn looping_kernel_wraps() {
+        // 1000 samples is not a whole number of 441 sample windows
+        let loop_len = 1000;
+        for seed in 0..50 {
+            let kernel = render_looping(100, 44100, loop_len, 1., seed);
+            assert!(kernel.iter().all(|(idx, _coeff)| *idx < loop_len));
+            assert!(kernel.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            assert!(kernel.len() >= 2 && kernel.len() <= 3);
+        }
+    }
+
+    #[test]
+    fn remove_dc_of_skewed_kernel() {
+        let mut kernel = VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(441, 44100, 3),
+            Choice::from_seed(0.8, 4),
+        )
+        .render(0, 44100, 1.);
+        assert!(kernel_dc(&kernel) > 100.);
+
+        remove_dc(&mut kernel);
+        assert!(kernel_dc(&kernel).abs() < 1e-3);
+        assert_eq!(kernel.len(), 441);
+    }
+
+    #[test]
+    fn validate_malformed_kernels() {
+        let good = vec![(0, 1.), (10, -1.), (99, 0.5)];
+        assert!(validate_sorted_kernel(&good, 99).is_ok());
+
+        let nan = vec![(0, 1.), (10, f32::NAN), (99, 0.5)];
+        assert!(matches!(
+            validate_kernel(&nan, 99),
+            Err(VelvetError::NonFiniteCoefficient { index: 10 })
+        ));
+
+        assert!(matches!(
+            validate_kernel(&good, 50),
+            Err(VelvetError::IndexOutOfRange {
+                index: 99,
+                max_allowed: 50
+            })
+        ));
+
+        let unsorted = vec![(0, 1.), (99, 0.5), (10, -1.)];
+        assert!(validate_kernel(&unsorted, 99).is_ok());
+        assert!(matches!(
+            validate_sorted_kernel(&unsorted, 99),
+            Err(VelvetError::Unsorted { index: 10 })
+        ));
+    }
+
+    #[test]
+    fn combined_len_sums_stages() {
+        let kernels = vec![
+            vec![(0, 1.), (5, -1.)],
+            vec![],
+            vec![(5, 1.), (9, 1.), (12, -1.)],
+        ];
+        assert_eq!(combined_len(&kernels), 5);
+        assert_eq!(combine_kernels(&kernels).len(), 4);
+    }
+
+    #[test]
+    fn fingerprint_identifies_parameters() {
+        let fingerprint = kernel_fingerprint(2000, 44100, 88200, 7);
+        assert_eq!(fingerprint, kernel_fingerprint(2000, 44100, 88200, 7));
+        assert_ne!(fingerprint, kernel_fingerprint(2001, 44100, 88200, 7));
+        assert_ne!(fingerprint, kernel_fingerprint(2000, 48000, 88200, 7));
+        assert_ne!(fingerprint, kernel_fingerprint(2000, 44100, 88201, 7));
+        assert_ne!(fingerprint, kernel_fingerprint(2000, 44100, 88200, 8));
+        assert_ne!(
+            kernel_fingerprint(1, 2, 0, 0),
+            kernel_fingerprint(2, 1, 0, 0)
+        );
+    }
+
+    #[test]
+    fn taper_attenuates_band_edges() {
+        let mut kernel: Vec<(usize, f32)> = (0..300).step_by(5).map(|idx| (idx, 1.)).collect();
+        taper_band_edges(&mut kernel, 100, 200, 20);
+
+        for (idx, coeff) in kernel.iter() {
+            if *idx < 100 || *idx >= 200 || (*idx >= 120 && *idx < 180) {
+                assert_eq!(*coeff, 1.);
+            } else {
+                assert!(*coeff < 1.);
+            }
+        }
+        assert_eq!(kernel[20], (100, 0.));
+        assert_eq!(kernel[22], (110, 0.5));
+        assert_eq!(kernel[37], (185, 0.7));
+    }
+
+    #[test]
+    fn kernel_as_c_source() {
+        let kernel = vec![(0, 1.), (10, -1.), (30, 0.5)];
+        let source = kernel_to_c_array(&kernel, "velvet");
+        assert_eq!(
+            source,
+            "const unsigned long velvet_len = 3;\n\
+             const unsigned long velvet_indices[3] = {0, 10, 30};\n\
+             const float velvet_coefficients[3] = {1.0f, -1.0f, 0.5f};\n"
+        );
+        assert_eq!(source.matches(',').count(), 4);
+
+        assert_eq!(
+            kernel_to_c_array(&[], "empty"),
+            "const unsigned long empty_len = 0;\n"
+        );
+    }
+
+    #[test]
+    fn kernel_as_events() {
+        let kernel = VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(441, 44100, 7),
+            Choice::from_seed(0.5, 8),
+        )
+        .render(0, 44100, 1.);
+        let events = locations_to_events(&kernel, 44100);
+        assert_eq!(events.len(), kernel.len());
+        for ((time, positive), (idx, coeff)) in events.iter().zip(&kernel) {
+            assert_eq!(*time, *idx as f64 / 44100.);
+            assert_eq!(*positive, *coeff == 1.);
+        }
+    }
+
+    #[test]
+    fn fractional_impulses_split_between_neighbours() {
+        assert_eq!(render_fractional(&[10.], 0.5), vec![(10, 0.5)]);
+
+        for fraction in [0.1, 0.25, 0.5, 0.75, 0.9] {
+            let kernel = render_fractional(&[20. + fraction], 1.);
+            let weight = |idx: usize| {
+                kernel
+                    .iter()
+                    .find(|(i, _coeff)| *i == idx)
+                    .map(|(_i, coeff)| *coeff)
+                    .unwrap()
+            };
+            let (below, above) = (weight(20), weight(21));
+            assert!((above / (below + above) - fraction).abs() < 0.05);
+            let nearest_energy = below * below + above * above;
+            assert!(nearest_energy > 0.9 * energy(&kernel));
+        }
+    }
+
+    #[test]
+    fn clamp_large_coefficients() {
+        let mut kernel = vec![(0, 1.5), (3, -0.25), (7, -2.), (9, 0.5), (12, 0.75)];
+        clamp_coefficients(&mut kernel, 0.75);
+        assert_eq!(
+            kernel,
+            vec![(0, 0.75), (3, -0.25), (7, -0.75), (9, 0.5), (12, 0.75)]
+        );
+    }
+
+    #[test]
+    fn matched_pair_has_equal_energy() {
+        let (a, b) = matched_pair(441, 2205, 44100, 44100, 3);
+        assert_eq!(a.len(), 441);
+        assert_eq!(b.len(), 2205);
+        assert!((energy(&a) - energy(&b)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn whitened_kernel_is_flatter() {
+        let classic = VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(1, WHITENED_KERNEL_WINDOW, 4),
+            Choice::from_seed(0.5, 5),
+        )
+        .render(0, 4096, 1.);
+        let whitened = whitened_kernel(4096, 4);
+        assert_eq!(whitened.len(), classic.len());
+        // still one impulse per window
+        assert!(whitened.windows(2).all(
+            |pair| pair[0].0 / WHITENED_KERNEL_WINDOW + 1 == pair[1].0 / WHITENED_KERNEL_WINDOW
+        ));
+        assert!(whitened
+            .iter()
+            .all(|(idx, coeff)| *idx < 4096 && coeff.abs() == 1.));
+        assert!(
+            crate::analysis::spectral_flatness(&whitened, 4096)
+                > crate::analysis::spectral_flatness(&classic, 4096) + 0.05
+        );
+    }
+
+    #[test]
+    fn whitened_kernel_of_short_lengths() {
+        for length in 0..8 {
+            for seed in 0..200 {
+                let kernel = whitened_kernel(length, seed);
+                assert!(kernel
+                    .iter()
+                    .all(|(idx, coeff)| *idx < length && coeff.abs() == 1.));
+            }
+        }
+    }
+}
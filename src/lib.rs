@@ -1,54 +1,598 @@
 extern crate rand;
 
 use rand::distributions::{Bernoulli, Distribution};
-use rand::rngs::{SmallRng, ThreadRng};
 use rand::{Rng, SeedableRng};
+use rand_distr::Exp;
+use std::fmt;
+
+pub mod analysis;
+pub mod convolve;
+pub mod envelope;
+mod fft;
+pub mod filters;
+pub mod granular;
+pub mod kernel;
+pub mod reverb;
+#[cfg(feature = "rt")]
+pub mod rt;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod wav;
+
+use kernel::Coefficient;
+
+/// Random number generator used by all generators.
+///
+/// With the `rand_pcg` feature enabled this is `Pcg64`, so the `from_seed` constructors produce
+/// identical sequences on every platform and across crate versions. Without it, `SmallRng` is
+/// used, which is faster but makes no portability guarantee for seeded sequences.
+#[cfg(feature = "rand_pcg")]
+pub type VelvetRng = rand_pcg::Pcg64;
+
+/// Random number generator used by all generators.
+///
+/// With the `rand_pcg` feature enabled this is `Pcg64`, so the `from_seed` constructors produce
+/// identical sequences on every platform and across crate versions. Without it, `SmallRng` is
+/// used, which is faster but makes no portability guarantee for seeded sequences.
+#[cfg(not(feature = "rand_pcg"))]
+pub type VelvetRng = rand::rngs::SmallRng;
+
+/// Errors reported by the crate
+#[derive(Debug)]
+pub enum VelvetError {
+    /// A kernel tap lies beyond the largest allowed index
+    IndexOutOfRange { index: usize, max_allowed: usize },
+    /// The kernel tap at `index` has a NaN or infinite coefficient
+    NonFiniteCoefficient { index: usize },
+    /// The kernel tap at `index` comes before the previous tap
+    Unsorted { index: usize },
+    /// Reading or writing a WAV file failed
+    Wav(hound::Error),
+}
+
+impl fmt::Display for VelvetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VelvetError::IndexOutOfRange { index, max_allowed } => write!(
+                f,
+                "kernel index {} exceeds the maximum of {}",
+                index, max_allowed
+            ),
+            VelvetError::NonFiniteCoefficient { index } => {
+                write!(f, "kernel coefficient at index {} is not finite", index)
+            }
+            VelvetError::Unsorted { index } => {
+                write!(f, "kernel index {} is out of order", index)
+            }
+            VelvetError::Wav(err) => write!(f, "WAV error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for VelvetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VelvetError::Wav(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<hound::Error> for VelvetError {
+    fn from(err: hound::Error) -> Self {
+        VelvetError::Wav(err)
+    }
+}
+
+/// Convert a gain in decibels to a linear factor
+pub fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
+
+/// Convert a linear gain factor to decibels. A gain of 0 gives negative infinity.
+pub fn linear_to_db(gain: f32) -> f32 {
+    20. * gain.abs().log10()
+}
+
+/// The impulse placement schemes, with their parameters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LocationKind {
+    /// Original Velvet Noise, one impulse per grid window
+    Original { density: usize },
+    /// Additive Random Noise, random spacing around the mean
+    Additive { density: f32, delta: f32 },
+    /// Totally Random Velvet Noise, impulses placed independently anywhere
+    TotallyRandom { density: f32 },
+}
 
 /// Original Velvet Noise impulse location iterator
-pub struct OVNImpulseLocations {
+#[derive(Clone)]
+pub struct OVNImpulseLocations<R = VelvetRng> {
     m: std::ops::RangeFrom<usize>,
     td: usize,
-    r1m: SmallRng,
+    /// Fractional part of the window length, carried across windows
+    td_fraction: f64,
+    r1m: R,
+    per_window: usize,
+    pending: Vec<usize>,
+    max_sample: usize,
+}
+
+impl<R> fmt::Debug for OVNImpulseLocations<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OVNImpulseLocations")
+            .field("td", &(self.td as f64 + self.td_fraction))
+            .field("m", &self.m.start)
+            .field("per_window", &self.per_window)
+            .field("max_sample", &self.max_sample)
+            .finish_non_exhaustive()
+    }
 }
 
 impl OVNImpulseLocations {
     /// density is non-zero pulses per second
     /// sample_rate is total samples per second
     pub fn new(density: usize, sample_rate: usize) -> OVNImpulseLocations {
+        OVNImpulseLocations::with_rng(density, sample_rate, VelvetRng::from_entropy())
+    }
+
+    /// Reproducible locations for a given seed
+    pub fn from_seed(density: usize, sample_rate: usize, seed: u64) -> OVNImpulseLocations {
+        OVNImpulseLocations::with_rng(density, sample_rate, VelvetRng::seed_from_u64(seed))
+    }
+
+    /// Reproducible locations for a full 32 byte seed, e.g. decoded from a shared token.
+    /// `Pcg64`, with the `rand_pcg` feature, takes the seed as it is. The default `SmallRng`
+    /// has a 16 byte seed, so the two halves are XORed together and every byte still counts.
+    pub fn from_seed_bytes(
+        density: usize,
+        sample_rate: usize,
+        seed: [u8; 32],
+    ) -> OVNImpulseLocations {
+        let mut rng_seed = <VelvetRng as SeedableRng>::Seed::default();
+        let rng_bytes = rng_seed.as_mut();
+        let len = rng_bytes.len();
+        for (i, byte) in seed.iter().enumerate() {
+            rng_bytes[i % len] ^= byte;
+        }
+        OVNImpulseLocations::with_rng(density, sample_rate, VelvetRng::from_seed(rng_seed))
+    }
+
+    /// As `new`, but the iterator ends instead of yielding a location beyond `max_sample`.
+    /// Locations that would overflow `usize` also end the iterator.
+    pub fn bounded(density: usize, sample_rate: usize, max_sample: usize) -> OVNImpulseLocations {
+        OVNImpulseLocations {
+            max_sample,
+            ..OVNImpulseLocations::new(density, sample_rate)
+        }
+    }
+
+    /// As `new`, with a fractional density. The fractional part of the window length is carried
+    /// across windows, so window lengths vary by a sample and the long-run density matches.
+    pub fn new_f32(density: f32, sample_rate: f32) -> OVNImpulseLocations {
+        OVNImpulseLocations::with_rng_f32(density, sample_rate, VelvetRng::from_entropy())
+    }
+
+    /// Reproducible locations with a fractional density for a given seed
+    pub fn from_seed_f32(density: f32, sample_rate: f32, seed: u64) -> OVNImpulseLocations {
+        OVNImpulseLocations::with_rng_f32(density, sample_rate, VelvetRng::seed_from_u64(seed))
+    }
+
+    /// Place impulses_per_window independent impulses in every window of window_samples.
+    /// Useful for densities so high that `sample_rate / density` would round to 0.
+    /// Impulses within a window may share an index.
+    pub fn multi(impulses_per_window: usize, window_samples: usize) -> OVNImpulseLocations {
+        assert!(
+            impulses_per_window > 0,
+            "impulses_per_window must be non-zero"
+        );
+        assert!(window_samples > 0, "window_samples must be non-zero");
+        OVNImpulseLocations {
+            m: (0..),
+            td: window_samples,
+            td_fraction: 0.,
+            r1m: VelvetRng::from_entropy(),
+            per_window: impulses_per_window,
+            pending: Vec::with_capacity(impulses_per_window),
+            max_sample: usize::MAX,
+        }
+    }
+}
+
+impl<R: Rng> OVNImpulseLocations<R> {
+    /// Locations drawn from the given random number generator
+    pub fn with_rng(density: usize, sample_rate: usize, rng: R) -> OVNImpulseLocations<R> {
         OVNImpulseLocations {
             m: (0..),
             td: sample_rate / density,
-            r1m: SmallRng::from_entropy(),
+            td_fraction: 0.,
+            r1m: rng,
+            per_window: 1,
+            pending: vec![],
+            max_sample: usize::MAX,
+        }
+    }
+
+    /// Locations with a fractional density drawn from the given random number generator, see
+    /// `new_f32`
+    pub fn with_rng_f32(density: f32, sample_rate: f32, rng: R) -> OVNImpulseLocations<R> {
+        let td = sample_rate as f64 / density as f64;
+        assert!(td >= 1., "density must not exceed sample_rate");
+        OVNImpulseLocations {
+            m: (0..),
+            td: td.floor() as usize,
+            td_fraction: td.fract(),
+            r1m: rng,
+            per_window: 1,
+            pending: vec![],
+            max_sample: usize::MAX,
         }
     }
 }
 
-impl Iterator for OVNImpulseLocations {
+impl<R> OVNImpulseLocations<R> {
+    /// Start and length of the next window, or None on overflow
+    fn next_window(&mut self) -> Option<(usize, usize)> {
+        let m = self.m.next().unwrap();
+        if self.td_fraction == 0. {
+            return Some((m.checked_mul(self.td)?, self.td));
+        }
+        let carried = |m: usize| (m as f64 * self.td_fraction) as usize;
+        let start = m.checked_mul(self.td)?.checked_add(carried(m))?;
+        Some((start, self.td + carried(m + 1) - carried(m)))
+    }
+
+    /// Index of the window holding the most recently yielded location, or 0 before the first
+    pub fn current_window(&self) -> usize {
+        self.m.start.saturating_sub(1)
+    }
+}
+
+impl<R: Rng> Iterator for OVNImpulseLocations<R> {
     type Item = usize;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let val = (self.m.next().unwrap() * self.td) + self.r1m.gen_range(0, self.td);
+        if self.per_window == 1 {
+            let (start, td) = self.next_window()?;
+            let val = start.checked_add(self.r1m.gen_range(0, td))?;
+            return Some(val).filter(|val| *val <= self.max_sample);
+        }
+
+        if self.pending.is_empty() {
+            let (start, td) = self.next_window()?;
+            for _ in 0..self.per_window {
+                let val = start.checked_add(self.r1m.gen_range(0, td))?;
+                self.pending.push(val);
+            }
+            // pop from the back in ascending order
+            self.pending.sort_unstable_by(|a, b| b.cmp(a));
+        }
+        self.pending.pop().filter(|val| *val <= self.max_sample)
+    }
+}
+
+/// OVN impulse locations grouped into consecutive chunks of `chunk_length` samples.
+/// Each item holds the locations falling in that chunk, relative to the chunk start.
+/// Iteration ends rather than overflowing once the chunk end would exceed `usize::MAX`.
+#[derive(Clone, Debug)]
+pub struct ChunkedOVNImpulseLocations {
+    locations: std::iter::Peekable<OVNImpulseLocations>,
+    base: usize,
+    chunk_length: usize,
+}
+
+impl ChunkedOVNImpulseLocations {
+    /// density is non-zero pulses per second
+    /// sample_rate is total samples per second
+    /// chunk_length is the number of samples per chunk
+    pub fn new(density: usize, sample_rate: usize, chunk_length: usize) -> Self {
+        Self {
+            locations: OVNImpulseLocations::new(density, sample_rate).peekable(),
+            base: 0,
+            chunk_length,
+        }
+    }
+
+    /// As `new`, with chunks of `chunk_ms` milliseconds rounded to the nearest sample
+    pub fn by_duration(density: usize, sample_rate: usize, chunk_ms: f32) -> Self {
+        let chunk_length = (chunk_ms * sample_rate as f32 / 1000.).round() as usize;
+        Self::new(density, sample_rate, chunk_length)
+    }
+
+    /// Number of samples in each chunk
+    pub fn chunk_length(&self) -> usize {
+        self.chunk_length
+    }
+}
+
+impl Iterator for ChunkedOVNImpulseLocations {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let end = self.base.checked_add(self.chunk_length)?;
+        let mut chunk = vec![];
+        while let Some(&x) = self.locations.peek() {
+            if x >= end {
+                break;
+            }
+            let relative = x
+                .checked_sub(self.base)
+                .expect("impulse location precedes the current chunk");
+            chunk.push(relative);
+            self.locations.next();
+        }
+        self.base = end;
+        Some(chunk)
+    }
+}
+
+/// Chunked OVN impulses with signs, for filling buffers directly.
+/// Each item holds the `(location, coefficient)` pairs falling in that chunk, with locations
+/// relative to the chunk start.
+#[derive(Clone, Debug)]
+pub struct ChunkedVelvetKernel {
+    locations: ChunkedOVNImpulseLocations,
+    choice: Choice,
+}
+
+impl ChunkedVelvetKernel {
+    pub fn new(locations: ChunkedOVNImpulseLocations, choice: Choice) -> Self {
+        Self { locations, choice }
+    }
+
+    /// Number of samples in each chunk
+    pub fn chunk_length(&self) -> usize {
+        self.locations.chunk_length()
+    }
+}
+
+impl Iterator for ChunkedVelvetKernel {
+    type Item = Vec<(usize, f32)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = self.locations.next()?;
+        let choice = &mut self.choice;
+        Some(
+            chunk
+                .into_iter()
+                .map(|idx| (idx, choice.next().unwrap()))
+                .collect(),
+        )
+    }
+}
+
+/// Chunked velvet kernel holding at most `max_taps` impulses per chunk, so the worst-case cost
+/// of processing a chunk is bounded.
+///
+/// Impulses beyond the cap are dropped from the end of the chunk. Once the cap falls below
+/// `chunk_length / td` chunks regularly lose taps and the effective density drops towards
+/// `max_taps` per chunk, with the gaps at the ends of chunks. A chunk can straddle up to
+/// `chunk_length / td + 2` windows, so a cap at least that high never drops anything.
+/// `dropped_fraction` reports how many impulses have been lost.
+#[derive(Clone, Debug)]
+pub struct CappedChunkedVelvetKernel {
+    kernel: ChunkedVelvetKernel,
+    max_taps: usize,
+    total: usize,
+    dropped: usize,
+}
+
+impl CappedChunkedVelvetKernel {
+    pub fn new(kernel: ChunkedVelvetKernel, max_taps: usize) -> Self {
+        Self {
+            kernel,
+            max_taps,
+            total: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Number of samples in each chunk
+    pub fn chunk_length(&self) -> usize {
+        self.kernel.chunk_length()
+    }
+
+    /// Fraction of the impulses generated so far that were dropped by the cap
+    pub fn dropped_fraction(&self) -> f32 {
+        if self.total == 0 {
+            0.
+        } else {
+            self.dropped as f32 / self.total as f32
+        }
+    }
+}
+
+impl Iterator for CappedChunkedVelvetKernel {
+    type Item = Vec<(usize, f32)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = self.kernel.next()?;
+        self.total += chunk.len();
+        self.dropped += chunk.len().saturating_sub(self.max_taps);
+        chunk.truncate(self.max_taps);
+        Some(chunk)
+    }
+}
+
+/// OVN impulse locations whose window length is pulled from a callback for every window,
+/// e.g. to follow a modulating clock. Each window holds one impulse, so the local density is
+/// the inverse of the current period. Periods of 0 are treated as 1.
+pub struct ModulatedOVN<F, R = VelvetRng> {
+    period_fn: F,
+    window_start: usize,
+    r1m: R,
+}
+
+impl<F, R> fmt::Debug for ModulatedOVN<F, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ModulatedOVN")
+            .field("window_start", &self.window_start)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F: FnMut() -> usize> ModulatedOVN<F> {
+    /// period_fn returns the length in samples of each successive window
+    pub fn new(period_fn: F) -> Self {
+        ModulatedOVN::with_rng(period_fn, VelvetRng::from_entropy())
+    }
+
+    /// Reproducible locations for a given seed
+    pub fn from_seed(period_fn: F, seed: u64) -> Self {
+        ModulatedOVN::with_rng(period_fn, VelvetRng::seed_from_u64(seed))
+    }
+}
+
+impl<F: FnMut() -> usize, R: Rng> ModulatedOVN<F, R> {
+    /// Locations drawn from the given random number generator
+    pub fn with_rng(period_fn: F, rng: R) -> Self {
+        Self {
+            period_fn,
+            window_start: 0,
+            r1m: rng,
+        }
+    }
+}
+
+impl<F: FnMut() -> usize, R: Rng> Iterator for ModulatedOVN<F, R> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let td = (self.period_fn)().max(1);
+        let val = self.window_start + self.r1m.gen_range(0, td);
+        self.window_start = self.window_start.checked_add(td)?;
         Some(val)
     }
 }
 
+/// OVN locations below `total_len` for a given seed, from the last down to the first, e.g. for
+/// building reverse reverb kernels. The sequence is the reverse of the forward locations from
+/// `OVNImpulseLocations::from_seed`, so the locations are generated up front.
+pub fn reverse_locations(
+    density: usize,
+    sample_rate: usize,
+    total_len: usize,
+    seed: u64,
+) -> impl Iterator<Item = usize> {
+    let forward: Vec<usize> = OVNImpulseLocations::from_seed(density, sample_rate, seed)
+        .take_while(|idx| *idx < total_len)
+        .collect();
+    forward.into_iter().rev()
+}
+
+/// First `n` OVN impulse locations for a given seed.
+/// Intended for golden-value regression tests; enable the `rand_pcg` feature so the values are
+/// the same on every platform.
+pub fn debug_first_n_locations(
+    seed: u64,
+    density: usize,
+    sample_rate: usize,
+    n: usize,
+) -> Vec<usize> {
+    OVNImpulseLocations::from_seed(density, sample_rate, seed)
+        .take(n)
+        .collect()
+}
+
+/// The first `k` locations of an impulse location iterator, with an exact length. Finite
+/// iterators, e.g. from `OVNImpulseLocations::bounded`, give fewer if they end first. The
+/// locations are generated up front so the length is known.
+pub fn take_impulses(
+    locations: impl Iterator<Item = usize>,
+    k: usize,
+) -> impl ExactSizeIterator<Item = usize> {
+    let taken: Vec<usize> = locations.take(k).collect();
+    taken.into_iter()
+}
+
+/// Only the locations for which `mask` is true, e.g. for rhythmic or sidechain-style gating
+pub fn gate(
+    locations: impl Iterator<Item = usize>,
+    mask: impl Fn(usize) -> bool,
+) -> impl Iterator<Item = usize> {
+    locations.filter(move |idx| mask(*idx))
+}
+
+/// Move each location a fraction `pull` in [0, 1] of the way towards the nearest of `onsets`,
+/// which must be sorted, e.g. to follow the transients of a recording. `pull = 1` snaps every
+/// location onto an onset. Locations may no longer be strictly increasing.
+pub fn align_to_onsets<'a>(
+    base: impl Iterator<Item = usize> + 'a,
+    onsets: &'a [usize],
+    pull: f32,
+) -> impl Iterator<Item = usize> + 'a {
+    assert!((0. ..=1.).contains(&pull), "pull must be in [0, 1]");
+    base.map(move |idx| {
+        let after = onsets.partition_point(|onset| *onset < idx);
+        let nearest = [after.checked_sub(1), Some(after)]
+            .iter()
+            .filter_map(|i| i.and_then(|i| onsets.get(i)))
+            .min_by_key(|onset| onset.abs_diff(idx))
+            .cloned();
+        match nearest {
+            Some(onset) => (idx as f32 + pull * (onset as f32 - idx as f32)).round() as usize,
+            None => idx,
+        }
+    })
+}
+
 /// Additive Random Noise impulse location iterator
+#[derive(Clone)]
 pub struct ARNImpulseLocations {
     m_prev: f32,
     td_minus_1: f32,
     delta: f32,
-    r1m: ThreadRng,
+    skew: f32,
+    r1m: VelvetRng,
+}
+
+impl fmt::Debug for ARNImpulseLocations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ARNImpulseLocations")
+            .field("delta", &self.delta)
+            .field("m_prev", &self.m_prev)
+            .finish_non_exhaustive()
+    }
 }
 
 impl ARNImpulseLocations {
     /// density is non-zero pulses per second
     /// sample_rate is total samples per second
     pub fn new(density: f32, sample_rate: f32, delta: f32) -> ARNImpulseLocations {
+        ARNImpulseLocations::with_bias(density, sample_rate, delta, 0.)
+    }
+
+    /// As `new`, but bias in [-1, 1] skews the random placement within each window.
+    /// Negative values place impulses earlier, positive values later.
+    pub fn with_bias(density: f32, sample_rate: f32, delta: f32, bias: f32) -> ARNImpulseLocations {
+        ARNImpulseLocations::with_rng(density, sample_rate, delta, bias, VelvetRng::from_entropy())
+    }
+
+    /// Reproducible locations for a given seed
+    pub fn from_seed(density: f32, sample_rate: f32, delta: f32, seed: u64) -> ARNImpulseLocations {
+        ARNImpulseLocations::with_rng(
+            density,
+            sample_rate,
+            delta,
+            0.,
+            VelvetRng::seed_from_u64(seed),
+        )
+    }
+
+    fn with_rng(
+        density: f32,
+        sample_rate: f32,
+        delta: f32,
+        bias: f32,
+        rng: VelvetRng,
+    ) -> ARNImpulseLocations {
+        assert!((-1. ..=1.).contains(&bias), "bias must be in [-1, 1]");
         ARNImpulseLocations {
             m_prev: 0.,
             td_minus_1: (sample_rate / density) - 1.,
-            delta: delta,
-            r1m: rand::thread_rng(),
+            delta,
+            skew: 2f32.powf(-bias),
+            r1m: rng,
         }
     }
 }
@@ -60,41 +604,272 @@ impl Iterator for ARNImpulseLocations {
         let val = self.m_prev
             + 1.
             + (self.td_minus_1 * (1. - self.delta))
-            + (2. * self.delta * self.td_minus_1 * self.r1m.gen::<f32>());
+            + (2. * self.delta * self.td_minus_1 * self.r1m.gen::<f32>().powf(self.skew));
+
+        // Bad parameters (e.g. delta > 1) could move backwards or produce NaN. End the
+        // sequence on NaN and never step less than one sample.
+        if val.is_nan() {
+            return None;
+        }
+        let val = val.max(self.m_prev + 1.);
+
         self.m_prev = val;
         Some(val as usize)
     }
 }
 
-/// Random sequence of negative/positive samples
-pub struct Choice(Bernoulli, SmallRng);
+/// Impulse locations from a homogeneous Poisson process, i.e. with exponentially distributed
+/// spacing. Arrival times are rounded to whole samples; an arrival rounding onto or before the
+/// previous location is bumped one sample later so locations stay strictly increasing.
+#[derive(Clone)]
+pub struct PoissonImpulseLocations {
+    arrival: f64,
+    prev: Option<usize>,
+    spacing: Exp<f64>,
+    rng: VelvetRng,
+}
+
+impl fmt::Debug for PoissonImpulseLocations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PoissonImpulseLocations")
+            .field("arrival", &self.arrival)
+            .field("prev", &self.prev)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PoissonImpulseLocations {
+    /// density is the mean number of pulses per second
+    /// sample_rate is total samples per second
+    pub fn new(density: f32, sample_rate: f32, seed: u64) -> PoissonImpulseLocations {
+        let rate = density as f64 / sample_rate as f64;
+        PoissonImpulseLocations {
+            arrival: 0.,
+            prev: None,
+            spacing: Exp::new(rate).expect("density must be positive"),
+            rng: VelvetRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Iterator for PoissonImpulseLocations {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.arrival += self.spacing.sample(&mut self.rng);
+        let val = self.arrival.round() as usize;
+        let val = match self.prev {
+            Some(prev) if val <= prev => prev.checked_add(1)?,
+            _ => val,
+        };
+        self.prev = Some(val);
+        Some(val)
+    }
+}
+
+/// Heuristic ARN `(density, delta)` comparable to OVN at `ovn_density`.
+///
+/// Both produce the same mean spacing between impulses. OVN spacing is the window length plus
+/// the difference of two uniform offsets, with variance of roughly `td^2 / 6`. ARN spacing
+/// jitters uniformly over `2 * delta * td`, so `delta = 1 / sqrt(2)` gives about the same
+/// variance. This ignores the rounding of the OVN window length to whole samples.
+pub fn arn_equivalent(ovn_density: usize) -> (f32, f32) {
+    (ovn_density as f32, std::f32::consts::FRAC_1_SQRT_2)
+}
+
+/// Random sequence of negative/positive samples.
+///
+/// Each sample is `1.` with probability `skew` and `-1.` otherwise, so `0.5` gives the classic
+/// balanced sequence, values above `0.5` bias the output positive and values below bias it
+/// negative. `skew` must lie in `[0, 1]`.
+///
+/// Signs can also be drawn from any other `Distribution<bool>`, e.g. a pattern or Markov
+/// chain, see `from_distribution`. `true` gives a positive sample.
+#[derive(Clone)]
+pub struct Choice<D = Bernoulli> {
+    distribution: D,
+    /// Probability behind the Bernoulli distribution, `None` for other distributions
+    skew: Option<f64>,
+    rng: VelvetRng,
+    /// Mean run length, and the chance of a positive and of a negative run ending at each
+    /// sample, see `run_length`
+    runs: Option<(f64, Bernoulli, Bernoulli)>,
+    current: Option<bool>,
+}
+
+impl<D> fmt::Debug for Choice<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("Choice");
+        if let Some(skew) = self.skew {
+            debug.field("skew", &skew);
+        }
+        debug.finish_non_exhaustive()
+    }
+}
 
 impl Choice {
     /// Crushed (skewed) sample choice
     pub fn crushed(skew: f64) -> Choice {
-        Choice(Bernoulli::new(skew).unwrap(), SmallRng::from_entropy())
+        Choice::with_rng(skew, VelvetRng::from_entropy())
+    }
+
+    /// Reproducible crushed sample choice for a given seed
+    pub fn from_seed(skew: f64, seed: u64) -> Choice {
+        Choice::with_rng(skew, VelvetRng::seed_from_u64(seed))
     }
 
     /// Classic sample choice
     pub fn classic() -> Choice {
         Choice::crushed(0.5)
     }
+
+    fn with_rng(skew: f64, rng: VelvetRng) -> Choice {
+        assert!((0. ..=1.).contains(&skew), "skew must be in [0, 1]");
+        Choice {
+            distribution: Bernoulli::new(skew).unwrap(),
+            skew: Some(skew),
+            rng,
+            runs: None,
+            current: None,
+        }
+    }
+
+    /// Sign sequence made of runs of identical samples, which colours the spectrum with
+    /// comb-like peaks. Run lengths are geometrically distributed and alternate in sign,
+    /// averaging `mean_run` samples. Positive runs are stretched and negative runs shortened
+    /// so that a fraction `skew` of the samples is still positive. `skew` must lie in `(0, 1)`
+    /// and neither run can average less than one sample, i.e. `2 * mean_run * skew` and
+    /// `2 * mean_run * (1 - skew)` must both be at least `1`.
+    pub fn run_length(skew: f64, mean_run: f64) -> Choice {
+        Choice::run_length_with_rng(skew, mean_run, VelvetRng::from_entropy())
+    }
+
+    /// Reproducible run-length sign sequence for a given seed, see `run_length`
+    pub fn run_length_from_seed(skew: f64, mean_run: f64, seed: u64) -> Choice {
+        Choice::run_length_with_rng(skew, mean_run, VelvetRng::seed_from_u64(seed))
+    }
+
+    fn run_length_with_rng(skew: f64, mean_run: f64, rng: VelvetRng) -> Choice {
+        let mut choice = Choice::with_rng(skew, rng);
+        choice.runs = Some(Choice::runs(skew, mean_run));
+        choice
+    }
+
+    fn runs(skew: f64, mean_run: f64) -> (f64, Bernoulli, Bernoulli) {
+        assert!(skew > 0. && skew < 1., "skew must be in (0, 1)");
+        let (positive_mean, negative_mean) = (2. * mean_run * skew, 2. * mean_run * (1. - skew));
+        assert!(
+            positive_mean >= 1. && negative_mean >= 1.,
+            "mean_run too short for skew, runs must average at least one sample"
+        );
+        let end_chance = |mean: f64| Bernoulli::new(1. / mean).unwrap();
+        (
+            mean_run,
+            end_chance(positive_mean),
+            end_chance(negative_mean),
+        )
+    }
+
+    /// Change the probability of each sample being positive, e.g. to morph between balanced
+    /// and crushed noise while running. The same limits apply as at construction.
+    pub fn set_skew(&mut self, skew: f64) {
+        assert!((0. ..=1.).contains(&skew), "skew must be in [0, 1]");
+        if let Some((mean_run, _, _)) = self.runs {
+            self.runs = Some(Choice::runs(skew, mean_run));
+        }
+        self.distribution = Bernoulli::new(skew).unwrap();
+        self.skew = Some(skew);
+    }
 }
 
-impl Iterator for Choice {
+impl<D> Choice<D> {
+    /// Probability of each sample being positive, if known. `None` for signs from
+    /// `from_distribution`, even a `Bernoulli` one, as the distribution is opaque.
+    pub fn positive_probability(&self) -> Option<f64> {
+        self.skew
+    }
+}
+
+impl<D: Distribution<bool>> Choice<D> {
+    /// Signs drawn from `distribution` using `rng`, e.g.
+    /// `Choice::from_distribution(pattern, VelvetRng::seed_from_u64(seed))`
+    pub fn from_distribution(distribution: D, rng: VelvetRng) -> Self {
+        Choice {
+            distribution,
+            skew: None,
+            rng,
+            runs: None,
+            current: None,
+        }
+    }
+
+    /// Discard the next `n` samples, e.g. to offset two identically seeded sequences
+    pub fn skip_ahead(&mut self, n: usize) {
+        for _ in 0..n {
+            self.next_positive();
+        }
+    }
+}
+
+impl<D: Distribution<bool>> Iterator for Choice<D> {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.0.sample(&mut self.1) {
+        match self.next_positive() {
             true => Some(1.),
             false => Some(-1.),
         }
     }
 }
 
+impl<D: Distribution<bool>> Choice<D> {
+    fn next_positive(&mut self) -> bool {
+        let (positive_end, negative_end) = match &self.runs {
+            Some((_mean_run, positive_end, negative_end)) => (positive_end, negative_end),
+            None => return self.distribution.sample(&mut self.rng),
+        };
+        let positive = match self.current {
+            Some(true) => !positive_end.sample(&mut self.rng),
+            Some(false) => negative_end.sample(&mut self.rng),
+            None => self.distribution.sample(&mut self.rng),
+        };
+        self.current = Some(positive);
+        positive
+    }
+}
+
+/// Classic signs scaled by an LFO evaluated once per window, see
+/// `VelvetNoise::with_amplitude_lfo`
+pub struct ModulatedChoice<F> {
+    choice: Choice,
+    lfo: F,
+    window: usize,
+}
+
+impl<F> fmt::Debug for ModulatedChoice<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ModulatedChoice")
+            .field("choice", &self.choice)
+            .field("window", &self.window)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F: FnMut(usize) -> f32> Iterator for ModulatedChoice<F> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sign = self.choice.next()?;
+        let gain = (self.lfo)(self.window);
+        self.window += 1;
+        Some(sign * gain)
+    }
+}
+
 /// Velvet Noise Kernal
 /// Iterator that will generate (index, coefficient) pairs.
 /// All indices not given in a pair are assumed to contain a 0 coefficient
+#[derive(Clone, Debug)]
 pub struct VelvetNoiseKernel<T: Iterator<Item = usize>, U: Iterator<Item = f32>> {
     indices: T,
     coefficients: U,
@@ -115,6 +890,14 @@ where
     }
 }
 
+impl VelvetNoiseKernel<std::vec::IntoIter<usize>, Choice> {
+    /// Kernel with exactly the given impulse locations, e.g. to reproduce a published kernel.
+    /// The kernel ends after the last location.
+    pub fn from_locations(locations: Vec<usize>, choice: Choice) -> Self {
+        Self::new(locations.into_iter(), choice)
+    }
+}
+
 impl<T, U> VelvetNoiseKernel<T, U>
 where
     T: Iterator<Item = usize>,
@@ -127,15 +910,61 @@ where
         }
     }
 
+    /// Impulses in `min_idx..max_idx` scaled by gain
     pub fn render(self, min_idx: usize, max_idx: usize, gain: f32) -> Vec<(usize, f32)> {
+        self.render_as(min_idx, max_idx, gain)
+    }
+
+    /// As `render`, with coefficients of another `Coefficient` type such as `f64`
+    pub fn render_as<S: Coefficient>(
+        self,
+        min_idx: usize,
+        max_idx: usize,
+        gain: S,
+    ) -> Vec<(usize, S)> {
         self.skip_while(|(idx, _coeff)| idx < &min_idx)
             .take_while(|(idx, _coeff)| idx < &max_idx)
+            .map(|(_idx, coeff)| (_idx, S::from_f32(coeff) * gain))
+            .collect()
+    }
+
+    /// As `render`, into a fixed-size array instead of a `Vec`. Returns the array and the number
+    /// of valid entries at its start. Impulses that don't fit in `N` entries are dropped.
+    pub fn render_array<S: Coefficient, const N: usize>(
+        self,
+        min_idx: usize,
+        max_idx: usize,
+        gain: S,
+    ) -> ([(usize, S); N], usize) {
+        let mut taps = [(0, S::ZERO); N];
+        let mut count = 0;
+        let impulses = self
+            .skip_while(|(idx, _coeff)| idx < &min_idx)
+            .take_while(|(idx, _coeff)| idx < &max_idx)
+            .take(N);
+        for (tap, (idx, coeff)) in taps.iter_mut().zip(impulses) {
+            *tap = (idx, S::from_f32(coeff) * gain);
+            count += 1;
+        }
+        (taps, count)
+    }
+
+    /// As `render`, with the gain given in decibels
+    pub fn render_db(self, min_idx: usize, max_idx: usize, gain_db: f32) -> Vec<(usize, f32)> {
+        self.render(min_idx, max_idx, db_to_linear(gain_db))
+    }
+
+    /// The first `count` impulses at or after `min_idx`, scaled by gain
+    pub fn render_count(self, min_idx: usize, count: usize, gain: f32) -> Vec<(usize, f32)> {
+        self.skip_while(|(idx, _coeff)| idx < &min_idx)
+            .take(count)
             .map(|(_idx, coeff)| (_idx, coeff * gain))
             .collect()
     }
 }
 
 /// Audio signal generated by the given kernel
+#[derive(Clone, Debug)]
 pub struct VelvetNoise<VelvetNoiseKernel> {
     kernel: VelvetNoiseKernel,
     next: (usize, f32),
@@ -149,11 +978,73 @@ where
 {
     fn from_kernel(mut kernel: VelvetNoiseKernel<T, U>) -> VelvetNoise<VelvetNoiseKernel<T, U>> {
         let next = kernel.next().unwrap();
-        VelvetNoise {
-            kernel: kernel,
-            n: 0,
-            next: next,
+        VelvetNoise { kernel, n: 0, next }
+    }
+
+    /// Write the next `out.len()` samples into `out`, continuing from previous calls.
+    /// Equivalent to pulling the same number of samples from the iterator, without any
+    /// allocation.
+    pub fn fill(&mut self, out: &mut [f32]) {
+        out.fill(0.);
+
+        let end = self.n + out.len();
+        while self.next.0 < end {
+            out[self.next.0 - self.n] = self.next.1;
+            self.next = self.kernel.next().unwrap();
         }
+
+        self.n = end;
+    }
+
+    /// Callback filling each buffer it is given with the next samples via `fill`, e.g. for a
+    /// `cpal` output stream. It does not allocate.
+    pub fn into_callback(mut self) -> impl FnMut(&mut [f32]) + Send
+    where
+        T: Send,
+        U: Send,
+    {
+        move |out: &mut [f32]| self.fill(out)
+    }
+
+    /// Stream of `(sample_index, is_impulse)`, e.g. for drawing the impulse pattern
+    pub fn impulse_mask(self) -> ImpulseMask<VelvetNoiseKernel<T, U>> {
+        ImpulseMask(self)
+    }
+}
+
+impl<F: FnMut(usize) -> f32>
+    VelvetNoise<VelvetNoiseKernel<OVNImpulseLocations, ModulatedChoice<F>>>
+{
+    /// Original velvet noise with each grid window's impulse scaled by `lfo(window_index)`,
+    /// for tremolo-like textures
+    pub fn with_amplitude_lfo(density: f32, sample_rate: f32, lfo: F) -> Self {
+        let kernel = VelvetNoiseKernel::new(
+            OVNImpulseLocations::new(density as usize, sample_rate as usize),
+            ModulatedChoice {
+                choice: Choice::classic(),
+                lfo,
+                window: 0,
+            },
+        );
+
+        VelvetNoise::from_kernel(kernel)
+    }
+}
+
+impl<T: Iterator<Item = usize>, D: Distribution<bool>>
+    VelvetNoise<VelvetNoiseKernel<T, Choice<D>>>
+{
+    /// Probability of each impulse being positive, if known, see `Choice::positive_probability`
+    pub fn skew(&self) -> Option<f64> {
+        self.kernel.coefficients.positive_probability()
+    }
+}
+
+impl<T: Iterator<Item = usize>> VelvetNoise<VelvetNoiseKernel<T, Choice>> {
+    /// Change the probability of each impulse being positive while running, see
+    /// `Choice::set_skew`. The sign of the next impulse may already have been drawn.
+    pub fn set_skew(&mut self, skew: f64) {
+        self.kernel.coefficients.set_skew(skew);
     }
 }
 
@@ -180,29 +1071,201 @@ where
     }
 }
 
-pub fn original_velvet_noise(
+/// Fixed-point velvet noise for targets without floating point output.
+/// Impulses are `magnitude` or `-magnitude`, all other samples are 0.
+#[derive(Clone, Debug)]
+pub struct VelvetNoiseI16<T: Iterator<Item = usize> = OVNImpulseLocations> {
+    locations: T,
+    choice: Choice,
+    magnitude: i16,
+    next: usize,
+    n: usize,
+}
+
+impl VelvetNoiseI16 {
+    /// Original velvet noise with the given impulse magnitude, e.g. `i16::MAX`.
+    /// `magnitude` must be greater than `i16::MIN`.
+    pub fn new(density: usize, sample_rate: usize, magnitude: i16) -> Self {
+        VelvetNoiseI16::from_locations(
+            OVNImpulseLocations::new(density, sample_rate),
+            Choice::classic(),
+            magnitude,
+        )
+    }
+}
+
+impl<T: Iterator<Item = usize>> VelvetNoiseI16<T> {
+    /// Fixed-point noise with impulses at the given locations and signs from `choice`.
+    /// `magnitude` must be greater than `i16::MIN`, whose negation does not fit in an `i16`.
+    pub fn from_locations(mut locations: T, choice: Choice, magnitude: i16) -> Self {
+        assert!(
+            magnitude > i16::MIN,
+            "magnitude must be greater than i16::MIN"
+        );
+        let next = locations.next().unwrap();
+        Self {
+            locations,
+            choice,
+            magnitude,
+            next,
+            n: 0,
+        }
+    }
+}
+
+impl<T: Iterator<Item = usize>> Iterator for VelvetNoiseI16<T> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let value = match self.n == self.next {
+            true => {
+                self.next = self.locations.next().unwrap();
+                match self.choice.next_positive() {
+                    true => self.magnitude,
+                    false => -self.magnitude,
+                }
+            }
+            false => 0,
+        };
+
+        self.n += 1;
+
+        Some(value)
+    }
+}
+
+/// Marks which samples of a velvet noise signal hold an impulse
+#[derive(Clone, Debug)]
+pub struct ImpulseMask<VelvetNoiseKernel>(VelvetNoise<VelvetNoiseKernel>);
+
+impl<T, U> Iterator for ImpulseMask<VelvetNoiseKernel<T, U>>
+where
+    T: Iterator<Item = usize>,
+    U: Iterator<Item = f32>,
+{
+    type Item = (usize, bool);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.0.n;
+        let is_impulse = n == self.0.next.0;
+        self.0.next();
+        Some((n, is_impulse))
+    }
+}
+
+/// Original velvet noise whose sample rate can change while running, e.g. when a host
+/// switches rate. The window length is rescaled so the density in impulses per second stays
+/// the same, without restarting the signal.
+#[derive(Clone, Debug)]
+pub struct RateAdaptingVelvet {
     density: f32,
-    sample_rate: f32,
-) -> VelvetNoise<VelvetNoiseKernel<OVNImpulseLocations, Choice>> {
-    let kernel = VelvetNoiseKernel::new(
-        OVNImpulseLocations::new(density as usize, sample_rate as usize),
-        Choice::classic(),
-    );
+    td: usize,
+    n: usize,
+    window_end: usize,
+    impulse_at: usize,
+    rng: VelvetRng,
+    choice: Choice,
+}
+
+impl RateAdaptingVelvet {
+    /// density is non-zero pulses per second
+    /// sample_rate is the initial total samples per second
+    pub fn new(density: f32, sample_rate: f32) -> Self {
+        Self::with_rng(
+            density,
+            sample_rate,
+            VelvetRng::from_entropy(),
+            Choice::classic(),
+        )
+    }
+
+    /// Reproducible noise for a given seed
+    pub fn from_seed(density: f32, sample_rate: f32, seed: u64) -> Self {
+        Self::with_rng(
+            density,
+            sample_rate,
+            VelvetRng::seed_from_u64(seed),
+            Choice::from_seed(0.5, seed.wrapping_add(1)),
+        )
+    }
+
+    fn with_rng(density: f32, sample_rate: f32, rng: VelvetRng, choice: Choice) -> Self {
+        let mut noise = Self {
+            density,
+            td: 1,
+            n: 0,
+            window_end: 0,
+            impulse_at: 0,
+            rng,
+            choice,
+        };
+        noise.set_sample_rate(sample_rate);
+        noise
+    }
+
+    /// Change the sample rate, keeping the density per second. The window in progress
+    /// finishes at the old length and the new length applies from the next window.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.td = ((sample_rate / self.density) as usize).max(1);
+    }
+}
+
+impl Iterator for RateAdaptingVelvet {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.n == self.window_end {
+            self.impulse_at = self.n + self.rng.gen_range(0, self.td);
+            self.window_end = self.n + self.td;
+        }
+        let sample = if self.n == self.impulse_at {
+            self.choice.next()?
+        } else {
+            0.
+        };
+        self.n += 1;
+        Some(sample)
+    }
+}
+
+/// Original velvet noise, the common type behind classic and crushed OVN
+pub type OriginalVelvetNoise = VelvetNoise<VelvetNoiseKernel<OVNImpulseLocations, Choice>>;
+
+impl OriginalVelvetNoise {
+    /// Classic OVN when `crush` is `None`, otherwise crushed OVN whose impulses are positive
+    /// with probability `crush`, see `Choice::crushed`
+    pub fn new(density: f32, sample_rate: f32, crush: Option<f64>) -> Self {
+        let kernel = VelvetNoiseKernel::new(
+            OVNImpulseLocations::new(density as usize, sample_rate as usize),
+            Choice::crushed(crush.unwrap_or(0.5)),
+        );
+
+        VelvetNoise::from_kernel(kernel)
+    }
+
+    /// Reproducible noise for a given seed
+    pub fn from_seed(density: f32, sample_rate: f32, crush: Option<f64>, seed: u64) -> Self {
+        let kernel = VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(density as usize, sample_rate as usize, seed),
+            Choice::from_seed(crush.unwrap_or(0.5), seed.wrapping_add(1)),
+        );
+
+        VelvetNoise::from_kernel(kernel)
+    }
+}
 
-    VelvetNoise::from_kernel(kernel)
+#[deprecated(note = "use `OriginalVelvetNoise::new(density, sample_rate, None)`")]
+pub fn original_velvet_noise(density: f32, sample_rate: f32) -> OriginalVelvetNoise {
+    OriginalVelvetNoise::new(density, sample_rate, None)
 }
 
+#[deprecated(note = "use `OriginalVelvetNoise::new(density, sample_rate, Some(skew))`")]
 pub fn crushed_original_velvet_noise(
     density: f32,
     sample_rate: f32,
     skew: f64,
-) -> VelvetNoise<VelvetNoiseKernel<OVNImpulseLocations, Choice>> {
-    let kernel = VelvetNoiseKernel::new(
-        OVNImpulseLocations::new(density as usize, sample_rate as usize),
-        Choice::crushed(skew),
-    );
-
-    VelvetNoise::from_kernel(kernel)
+) -> OriginalVelvetNoise {
+    OriginalVelvetNoise::new(density, sample_rate, Some(skew))
 }
 
 pub fn additive_velvet_noise(
@@ -232,6 +1295,136 @@ pub fn crushed_additive_velvet_noise(
     VelvetNoise::from_kernel(kernel)
 }
 
+/// Original velvet noise through a differencing filter, for bright, airy textures
+pub fn blue_velvet(density: f32, sample_rate: f32) -> filters::Filtered<OriginalVelvetNoise> {
+    filters::Biquad::violet().apply(OriginalVelvetNoise::new(density, sample_rate, None))
+}
+
+/// Impulses per second of `velvet_dither`
+pub const DITHER_DENSITY: usize = 100;
+
+/// `n` samples of sparse dither, e.g. to add before requantizing. Impulses of `amplitude`,
+/// typically around 1 LSB, with random signs at `DITHER_DENSITY` per second.
+pub fn velvet_dither(n: usize, amplitude: f32, sample_rate: usize, seed: u64) -> Vec<f32> {
+    let kernel = VelvetNoiseKernel::new(
+        OVNImpulseLocations::from_seed(DITHER_DENSITY, sample_rate, seed),
+        Choice::from_seed(0.5, seed.wrapping_add(1)),
+    )
+    .render(0, n, amplitude);
+    kernel::densify(&kernel, n)
+}
+
+/// `n` samples of original velvet noise scaled so their RMS is `target_rms`. The RMS of velvet
+/// noise depends on density and on how many impulses land in the block, so the gain is measured
+/// from the generated block itself. A block without impulses stays silent.
+pub fn velvet_with_target_rms(
+    density: usize,
+    sample_rate: usize,
+    n: usize,
+    target_rms: f32,
+    seed: u64,
+) -> Vec<f32> {
+    let kernel = VelvetNoiseKernel::new(
+        OVNImpulseLocations::from_seed(density, sample_rate, seed),
+        Choice::from_seed(0.5, seed.wrapping_add(1)),
+    )
+    .render(0, n, 1.);
+    let rms = (kernel.len() as f32 / n as f32).sqrt();
+    let gain = if kernel.is_empty() {
+        0.
+    } else {
+        target_rms / rms
+    };
+    kernel::densify(&kernel, n)
+        .into_iter()
+        .map(|x| x * gain)
+        .collect()
+}
+
+/// Sample-wise sum of two signals, ending with the shorter one
+pub fn mix(
+    a: impl Iterator<Item = f32>,
+    b: impl Iterator<Item = f32>,
+) -> impl Iterator<Item = f32> {
+    a.zip(b).map(|(x, y)| x + y)
+}
+
+/// `a` followed by `b`, overlapping the last `fade_samples` of `a` with the first of `b` under
+/// an equal-power crossfade, e.g. to stitch generated blocks without a click. The result is
+/// `a.len() + b.len() - fade_samples` long. The fade is shortened to fit the shorter input.
+pub fn concat_crossfade(a: &[f32], b: &[f32], fade_samples: usize) -> Vec<f32> {
+    let fade = fade_samples.min(a.len()).min(b.len());
+    let seam = a.len() - fade;
+    let mut out = Vec::with_capacity(a.len() + b.len() - fade);
+    out.extend_from_slice(&a[..seam]);
+    out.extend(
+        a[seam..]
+            .iter()
+            .zip(&b[..fade])
+            .enumerate()
+            .map(|(n, (x, y))| {
+                let angle = std::f32::consts::FRAC_PI_2 * n as f32 / fade as f32;
+                x * angle.cos() + y * angle.sin()
+            }),
+    );
+    out.extend_from_slice(&b[fade..]);
+    out
+}
+
+/// Adaptors for impulse location and signal iterators, for fluent chains such as
+/// `OVNImpulseLocations::new(density, sample_rate).until_sample(n).as_seconds(sample_rate)`
+pub trait VelvetIteratorExt: Iterator + Sized {
+    /// Locations before sample `n`
+    fn until_sample(self, n: usize) -> impl Iterator<Item = usize>
+    where
+        Self: Iterator<Item = usize>,
+    {
+        self.take_while(move |idx| *idx < n)
+    }
+
+    /// Locations converted from samples to seconds
+    #[allow(clippy::wrong_self_convention)]
+    fn as_seconds(self, sample_rate: usize) -> impl Iterator<Item = f32>
+    where
+        Self: Iterator<Item = usize>,
+    {
+        self.map(move |idx| idx as f32 / sample_rate as f32)
+    }
+
+    /// Samples multiplied by gain
+    fn scaled(self, gain: f32) -> impl Iterator<Item = f32>
+    where
+        Self: Iterator<Item = f32>,
+    {
+        self.map(move |x| x * gain)
+    }
+
+    /// Samples rounded to the nearest step of a signed `bits`-bit quantizer, i.e. multiples of
+    /// `2^(1 - bits)`
+    fn quantize(self, bits: u32) -> impl Iterator<Item = f32>
+    where
+        Self: Iterator<Item = f32>,
+    {
+        let steps = 2f32.powi(bits as i32 - 1);
+        self.map(move |x| (x * steps).round() / steps)
+    }
+}
+
+impl<I: Iterator> VelvetIteratorExt for I {}
+
+/// Stereo frames with the signal on the left and the same signal delayed by `delay_samples` on
+/// the right, for a Haas-style width effect. The right channel starts with silence.
+pub fn haas_stereo(
+    gen: impl Iterator<Item = f32>,
+    delay_samples: usize,
+) -> impl Iterator<Item = [f32; 2]> {
+    let mut delay_line: std::collections::VecDeque<f32> = vec![0.; delay_samples].into();
+    gen.map(move |left| {
+        delay_line.push_back(left);
+        [left, delay_line.pop_front().unwrap()]
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -247,7 +1440,7 @@ mod tests {
 
     fn spread(data: &[f32]) -> f32 {
         let dev = (0..data.len() - 1)
-            .map(|i| (*data)[i + 1] as f32 - (*data)[i] as f32)
+            .map(|i| (*data)[i + 1] - (*data)[i])
             .collect::<Vec<f32>>();
 
         let max = dev.iter().cloned().fold(f32::NAN, f32::max);
@@ -259,7 +1452,7 @@ mod tests {
     fn save(sample_rate: u32, data: Vec<f32>, filepath: &str) {
         let spec = hound::WavSpec {
             channels: 1,
-            sample_rate: sample_rate,
+            sample_rate,
             bits_per_sample: 32,
             sample_format: hound::SampleFormat::Float,
         };
@@ -292,6 +1485,466 @@ mod tests {
         assert_eq!(num_impulses / seconds, density);
     }
 
+    #[test]
+    fn seeded_locations_are_reproducible() {
+        let a = OVNImpulseLocations::from_seed(2000, 96000, 42).take(100);
+        let b = OVNImpulseLocations::from_seed(2000, 96000, 42).take(100);
+        assert!(a.eq(b));
+
+        let a = ARNImpulseLocations::from_seed(2000., 96000., 0.5, 42).take(100);
+        let b = ARNImpulseLocations::from_seed(2000., 96000., 0.5, 42).take(100);
+        assert!(a.eq(b));
+    }
+
+    #[cfg(feature = "rand_pcg")]
+    #[test]
+    fn seeded_locations_are_portable() {
+        let locs = OVNImpulseLocations::from_seed(2000, 96000, 42)
+            .take(8)
+            .collect::<Vec<usize>>();
+        assert_eq!(locs, vec![11, 84, 121, 190, 210, 249, 313, 374]);
+    }
+
+    // Golden vectors generated with the `rand_pcg` feature. If these change, the generation
+    // algorithm has changed and every seeded kernel in the wild will render differently.
+    #[cfg(feature = "rand_pcg")]
+    const GOLDEN_OVN_SEED_0: [usize; 16] = [
+        6, 64, 130, 185, 201, 264, 327, 337, 414, 441, 507, 528, 596, 666, 698, 720,
+    ];
+
+    #[cfg(feature = "rand_pcg")]
+    const GOLDEN_OVN_SEED_1234: [usize; 16] = [
+        34, 131, 249, 372, 489, 586, 650, 792, 809, 946, 1024, 1104, 1208, 1309, 1489, 1585,
+    ];
+
+    #[cfg(feature = "rand_pcg")]
+    #[test]
+    fn golden_locations() {
+        assert_eq!(
+            debug_first_n_locations(0, 2000, 96000, 16),
+            GOLDEN_OVN_SEED_0.to_vec()
+        );
+        assert_eq!(
+            debug_first_n_locations(1234, 441, 44100, 16),
+            GOLDEN_OVN_SEED_1234.to_vec()
+        );
+    }
+
+    #[test]
+    fn chunked_locations_are_relative() {
+        let chunks = ChunkedOVNImpulseLocations::new(441, 44100, 256)
+            .take(100)
+            .collect::<Vec<Vec<usize>>>();
+        assert!(chunks.iter().flatten().all(|x| *x < 256));
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), 256);
+    }
+
+    #[test]
+    fn chunked_kernel_has_signs() {
+        let chunks = ChunkedVelvetKernel::new(
+            ChunkedOVNImpulseLocations::new(441, 44100, 256),
+            Choice::classic(),
+        )
+        .take(100)
+        .collect::<Vec<Vec<(usize, f32)>>>();
+        assert!(chunks.iter().flatten().all(|(idx, _coeff)| *idx < 256));
+        assert!(chunks
+            .iter()
+            .flatten()
+            .all(|(_idx, coeff)| coeff.abs() == 1.));
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), 256);
+    }
+
+    #[test]
+    fn capped_chunks() {
+        // td of 100 puts 2 to 4 impulses in every chunk, mostly 2 or 3
+        let mut chunks = CappedChunkedVelvetKernel::new(
+            ChunkedVelvetKernel::new(
+                ChunkedOVNImpulseLocations::new(441, 44100, 256),
+                Choice::classic(),
+            ),
+            2,
+        );
+        assert!(chunks.by_ref().take(1000).all(|chunk| chunk.len() <= 2));
+        let dropped = chunks.dropped_fraction();
+        assert!(dropped > 0.1 && dropped < 0.35);
+
+        let mut uncapped = CappedChunkedVelvetKernel::new(
+            ChunkedVelvetKernel::new(
+                ChunkedOVNImpulseLocations::new(441, 44100, 256),
+                Choice::classic(),
+            ),
+            4,
+        );
+        let count = uncapped.by_ref().take(1000).flatten().count();
+        assert!((2559..=2560).contains(&count));
+        assert_eq!(uncapped.dropped_fraction(), 0.);
+    }
+
+    #[test]
+    fn modulated_ovn_tracks_period() {
+        // sawtooth period ramping from 20 to 218 samples every 100 windows
+        let periods: Vec<usize> = (0..1000).map(|k| 20 + (k % 100) * 2).collect();
+        let mut period_iter = periods.clone().into_iter();
+        let locations: Vec<usize> = ModulatedOVN::from_seed(move || period_iter.next().unwrap(), 5)
+            .take(periods.len())
+            .collect();
+
+        assert!(locations.windows(2).all(|pair| pair[0] < pair[1]));
+        for k in (0..periods.len() - 10).step_by(10) {
+            let span = (locations[k + 10] - locations[k]) as f32;
+            let expected: usize = periods[k..k + 10].iter().sum();
+            let density = 10. / span;
+            let expected_density = 10. / expected as f32;
+            assert_close_enough!(density, expected_density, expected_density * 0.25);
+        }
+    }
+
+    #[test]
+    fn ovn_from_seed_bytes() {
+        let mut seed = [0u8; 32];
+        seed.iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = (i * 37) as u8);
+        let a: Vec<usize> = OVNImpulseLocations::from_seed_bytes(441, 44100, seed)
+            .take(1000)
+            .collect();
+        let b: Vec<usize> = OVNImpulseLocations::from_seed_bytes(441, 44100, seed)
+            .take(1000)
+            .collect();
+        assert_eq!(a, b);
+
+        seed.reverse();
+        let c: Vec<usize> = OVNImpulseLocations::from_seed_bytes(441, 44100, seed)
+            .take(1000)
+            .collect();
+        assert_ne!(a, c);
+
+        // the last bytes matter too, whatever the size of the generator's own seed
+        seed.reverse();
+        seed[31] = seed[31].wrapping_add(0x80);
+        let d: Vec<usize> = OVNImpulseLocations::from_seed_bytes(441, 44100, seed)
+            .take(1000)
+            .collect();
+        assert_ne!(a, d);
+    }
+
+    #[test]
+    fn velvet_dither_is_sparse() {
+        let lsb = 1. / 32768.;
+        let dither = velvet_dither(96000, lsb, 48000, 3);
+        assert_eq!(dither.len(), 96000);
+        assert!(dither.iter().all(|x| *x == 0. || x.abs() == lsb));
+        assert_eq!(
+            analysis::observed_density(&dither, 48000),
+            DITHER_DENSITY as f32
+        );
+    }
+
+    #[test]
+    fn choice_from_custom_distribution() {
+        struct AlwaysPositive;
+        impl Distribution<bool> for AlwaysPositive {
+            fn sample<R: Rng + ?Sized>(&self, _rng: &mut R) -> bool {
+                true
+            }
+        }
+
+        let choice = Choice::from_distribution(AlwaysPositive, VelvetRng::seed_from_u64(0));
+        let kernel = VelvetNoiseKernel::new(OVNImpulseLocations::new(441, 44100), choice)
+            .render(0, 44100, 1.);
+        assert_eq!(kernel.len(), 441);
+        assert!(kernel.iter().all(|(_idx, coeff)| *coeff == 1.));
+    }
+
+    #[test]
+    fn choice_from_bernoulli_distribution() {
+        let choice =
+            Choice::from_distribution(Bernoulli::new(0.3).unwrap(), VelvetRng::seed_from_u64(1));
+        assert_eq!(choice.positive_probability(), None);
+
+        let noise = VelvetNoise::from_kernel(VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(2000, 96000, 2),
+            choice,
+        ));
+        assert_eq!(noise.skew(), None);
+        let positive = noise.take(96000 * 10).filter(|x| *x > 0.).count();
+        assert_close_enough!(positive as f32 / 20000., 0.3, 0.02);
+    }
+
+    #[test]
+    fn concat_crossfade_overlaps_seam() {
+        let a = vec![1f32; 100];
+        let b = vec![-0.5f32; 50];
+        let joined = concat_crossfade(&a, &b, 20);
+        assert_eq!(joined.len(), 130);
+        assert!(joined[..80].iter().all(|x| *x == 1.));
+        assert!(joined[100..].iter().all(|x| *x == -0.5));
+        for n in 0..20 {
+            let angle = std::f32::consts::FRAC_PI_2 * n as f32 / 20.;
+            let expected = angle.cos() - 0.5 * angle.sin();
+            assert_close_enough!(joined[80 + n], expected, 1e-6);
+        }
+
+        assert_eq!(concat_crossfade(&a, &b, 0).len(), 150);
+        assert_eq!(concat_crossfade(&a, &b, 80).len(), 100);
+    }
+
+    #[test]
+    fn velvet_matches_target_rms() {
+        for (density, n) in [(441, 44100), (2000, 10000), (50, 1234)] {
+            let noise = velvet_with_target_rms(density, 44100, n, 0.25, 9);
+            assert_eq!(noise.len(), n);
+            let rms = (noise.iter().map(|x| x * x).sum::<f32>() / n as f32).sqrt();
+            assert_close_enough!(rms, 0.25, 1e-4);
+        }
+        assert!(velvet_with_target_rms(1, 44100, 10, 0.25, 9)
+            .iter()
+            .all(|x| *x == 0.));
+    }
+
+    #[test]
+    fn callback_continues_stream() {
+        let kernel = || {
+            VelvetNoiseKernel::new(
+                OVNImpulseLocations::from_seed(2000, 44100, 4),
+                Choice::from_seed(0.5, 5),
+            )
+        };
+        let expected: Vec<f32> = VelvetNoise::from_kernel(kernel()).take(4410).collect();
+
+        fn assert_send<T: Send>(t: T) -> T {
+            t
+        }
+        let mut callback = assert_send(VelvetNoise::from_kernel(kernel()).into_callback());
+        let mut output = vec![];
+        for size in [64, 1, 512, 333, 3500] {
+            let mut buffer = vec![1f32; size];
+            callback(&mut buffer);
+            output.extend(buffer);
+        }
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn original_velvet_noise_crush_toggle() {
+        let classic: Vec<f32> = VelvetNoise::from_kernel(VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(2000, 44100, 3),
+            Choice::from_seed(0.5, 4),
+        ))
+        .take(44100)
+        .collect();
+        let crushed: Vec<f32> = VelvetNoise::from_kernel(VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(2000, 44100, 3),
+            Choice::from_seed(0.75, 4),
+        ))
+        .take(44100)
+        .collect();
+
+        let toggled = |crush| -> Vec<f32> {
+            OriginalVelvetNoise::from_seed(2000., 44100., crush, 3)
+                .take(44100)
+                .collect()
+        };
+        assert_eq!(toggled(None), classic);
+        assert_eq!(toggled(Some(0.75)), crushed);
+        assert_ne!(classic, crushed);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_constructors_still_work() {
+        let classic = original_velvet_noise(441., 44100.);
+        assert_eq!(classic.skew(), Some(0.5));
+        assert_eq!(classic.take(44100).filter(|x| *x != 0.).count(), 441);
+        assert_eq!(
+            crushed_original_velvet_noise(441., 44100., 0.75).skew(),
+            Some(0.75)
+        );
+    }
+
+    #[test]
+    fn ovn_current_window() {
+        let mut locations = OVNImpulseLocations::from_seed(441, 44100, 0);
+        for m in 0..100 {
+            let location = locations.next().unwrap();
+            assert_eq!(locations.current_window(), m);
+            assert_eq!(location / 100, m);
+        }
+    }
+
+    #[test]
+    fn bounded_locations_end_at_max_sample() {
+        let locations: Vec<usize> = OVNImpulseLocations::bounded(10, 1000, 499).collect();
+        assert_eq!(locations.len(), 5);
+        assert!(locations.iter().all(|x| *x <= 499));
+
+        for _ in 0..100 {
+            let locations: Vec<usize> = OVNImpulseLocations::bounded(10, 1000, 455).collect();
+            assert!(locations.len() == 4 || locations.len() == 5);
+            assert!(locations.iter().all(|x| *x <= 455));
+        }
+    }
+
+    #[test]
+    fn fractional_density() {
+        let sample_rate = 44100.;
+        let seconds = 100;
+        let impulses = OVNImpulseLocations::from_seed_f32(1500.5, sample_rate, 4)
+            .take_while(|x| *x < seconds * sample_rate as usize)
+            .count();
+        assert_close_enough!(impulses as f32 / seconds as f32, 1500.5, 0.01);
+
+        let locations: Vec<usize> = OVNImpulseLocations::from_seed_f32(1500.5, sample_rate, 5)
+            .take(10_000)
+            .collect();
+        assert!(locations.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(
+            OVNImpulseLocations::from_seed_f32(1500.5, sample_rate, 5)
+                .take(10_000)
+                .collect::<Vec<usize>>(),
+            locations
+        );
+    }
+
+    #[test]
+    fn gate_suppresses_masked_locations() {
+        let length = 44100;
+        let locations = OVNImpulseLocations::new(441, 44100).take_while(|idx| *idx < length);
+        let gated: Vec<usize> = gate(locations, |idx| idx < length / 2).collect();
+        assert!(gated.len() >= 220 && gated.len() <= 221);
+        assert!(gated.iter().all(|idx| *idx < length / 2));
+    }
+
+    #[test]
+    fn align_locations_to_onsets() {
+        let onsets = [120, 1000, 1500, 4000];
+        let base = || OVNImpulseLocations::from_seed(441, 44100, 2).take(50);
+        let locations: Vec<usize> = base().collect();
+
+        let snapped: Vec<usize> = align_to_onsets(base(), &onsets, 1.).collect();
+        assert!(snapped.iter().all(|idx| onsets.contains(idx)));
+        assert_eq!(snapped[0], 120);
+        assert_eq!(snapped[49], 4000);
+
+        let unchanged: Vec<usize> = align_to_onsets(base(), &onsets, 0.).collect();
+        assert_eq!(unchanged, locations);
+
+        let halfway: Vec<usize> =
+            align_to_onsets(vec![1100, 1300].into_iter(), &onsets, 0.5).collect();
+        assert_eq!(halfway, vec![1050, 1400]);
+    }
+
+    #[test]
+    fn reverse_locations_mirror_forward() {
+        let reversed: Vec<usize> = reverse_locations(441, 44100, 10_000, 6).collect();
+        assert_eq!(reversed.len(), 100);
+        assert!(reversed.windows(2).all(|pair| pair[0] > pair[1]));
+
+        let mut forward: Vec<usize> = OVNImpulseLocations::from_seed(441, 44100, 6)
+            .take_while(|idx| *idx < 10_000)
+            .collect();
+        forward.reverse();
+        assert_eq!(reversed, forward);
+    }
+
+    #[test]
+    fn chunked_locations_by_duration() {
+        let chunks = ChunkedOVNImpulseLocations::by_duration(441, 44100, 10.);
+        assert_eq!(chunks.chunk_length(), 441);
+        assert!(chunks.take(100).flatten().all(|x| x < 441));
+
+        let chunks = ChunkedOVNImpulseLocations::by_duration(441, 48000, 2.99);
+        assert_eq!(chunks.chunk_length(), 144);
+    }
+
+    #[test]
+    fn chunked_locations_run_for_billions_of_samples() {
+        let chunk_length = 1_000_000_000;
+        let chunks = ChunkedOVNImpulseLocations::new(1, 48000, chunk_length);
+        let mut total = 0;
+        for chunk in chunks.take(5) {
+            assert!(chunk.iter().all(|x| *x < chunk_length));
+            total += chunk.len();
+        }
+        assert_close_enough!(total as f32, 5. * chunk_length as f32 / 48000., 1.);
+    }
+
+    #[test]
+    fn multiple_impulses_per_window() {
+        let per_window = 4;
+        let window_samples = 10;
+        let locs: Vec<usize> = OVNImpulseLocations::multi(per_window, window_samples)
+            .take_while(|loc| *loc < 100_000)
+            .collect();
+        assert_eq!(locs.len(), 100_000 / window_samples * per_window);
+        for pair in locs.windows(2) {
+            assert_le!(pair[0], pair[1]);
+        }
+
+        // 4 impulses per sample, well beyond what `new` can represent
+        let locs: Vec<usize> = OVNImpulseLocations::multi(per_window, 1)
+            .take(400)
+            .collect();
+        assert_eq!(locs[399], 99);
+    }
+
+    #[test]
+    fn take_exact_impulses() {
+        let locs = take_impulses(OVNImpulseLocations::new(2000, 96000), 37);
+        assert_eq!(locs.len(), 37);
+        assert_eq!(locs.count(), 37);
+
+        // a finite generator ends early instead of panicking
+        let locs = take_impulses(OVNImpulseLocations::bounded(441, 44100, 1000), 50);
+        let len = locs.len();
+        assert!(len == 10 || len == 11);
+        assert_eq!(locs.count(), len);
+    }
+
+    #[test]
+    fn cloned_generators_continue_identically() {
+        let mut locs = OVNImpulseLocations::from_seed(2000, 96000, 1);
+        locs.nth(5);
+        assert!(locs.clone().take(100).eq(locs.take(100)));
+
+        let mut locs = ARNImpulseLocations::from_seed(2000., 96000., 0.5, 1);
+        locs.nth(5);
+        assert!(locs.clone().take(100).eq(locs.take(100)));
+
+        let mut choice = Choice::from_seed(0.5, 1);
+        choice.nth(5);
+        assert!(choice.clone().take(100).eq(choice.take(100)));
+
+        let mut noise = OriginalVelvetNoise::new(2000., 96000., None);
+        noise.nth(1000);
+        assert!(noise.clone().take(10000).eq(noise.take(10000)));
+    }
+
+    #[test]
+    fn debug_formatting() {
+        let mut locs = OVNImpulseLocations::new(441, 44100);
+        locs.next();
+        let formatted = format!("{:?}", locs);
+        assert!(formatted.contains("td: 100"));
+        assert!(formatted.contains("m: 1"));
+
+        let formatted = format!("{:?}", ARNImpulseLocations::new(441., 44100., 0.25));
+        assert!(formatted.contains("delta: 0.25"));
+        assert!(formatted.contains("m_prev: 0.0"));
+
+        let formatted = format!("{:?}", Choice::crushed(0.75));
+        assert!(formatted.contains("skew: 0.75"));
+
+        let formatted = format!("{:?}", OriginalVelvetNoise::new(441., 44100., None));
+        assert!(formatted.contains("VelvetNoise"));
+        assert!(formatted.contains("td: 100"));
+        assert!(formatted.contains("skew: 0.5"));
+
+        let formatted = format!("{:?}", ChunkedOVNImpulseLocations::new(441, 44100, 64));
+        assert!(formatted.contains("chunk_length: 64"));
+    }
+
     #[test]
     fn iter_arn_locations() {
         // Run iterator for a long time and check that the average impulse density is correct
@@ -326,6 +1979,38 @@ mod tests {
         assert_close_enough!(spread(impulses3.as_slice()), max_spread * 0.5, 2.);
     }
 
+    #[test]
+    fn arn_negative_bias_rushes_impulses() {
+        let density = 2000.;
+        let sample_rate = 96000.;
+        let n = 10_000;
+
+        let mean_interval = |bias: f32| {
+            let locs = ARNImpulseLocations::with_bias(density, sample_rate, 1., bias)
+                .take(n)
+                .collect::<Vec<usize>>();
+            for pair in locs.windows(2) {
+                assert_gt!(pair[1], pair[0]);
+            }
+            (locs[n - 1] - locs[0]) as f32 / (n - 1) as f32
+        };
+
+        assert_lt!(mean_interval(-1.), mean_interval(0.));
+    }
+
+    #[test]
+    fn arn_survives_bad_parameters() {
+        let locs: Vec<usize> = ARNImpulseLocations::new(2000., 96000., 5.)
+            .take(10_000)
+            .collect();
+        for pair in locs.windows(2) {
+            assert_gt!(pair[1], pair[0]);
+        }
+
+        let mut locs = ARNImpulseLocations::new(2000., 96000., f32::NAN);
+        assert_eq!(locs.next(), None);
+    }
+
     #[test]
     fn classic_choice_is_even() {
         let c = Choice::classic();
@@ -347,6 +2032,245 @@ mod tests {
         assert_close_enough!(total / 1_000_000., -0.5, 0.01);
     }
 
+    #[test]
+    fn choice_positive_probability() {
+        let c = Choice::crushed(0.9);
+        assert_eq!(c.positive_probability(), Some(0.9));
+        let positive = c.take(1_000_000).filter(|x| *x > 0.).count();
+        assert_close_enough!(positive as f32 / 1_000_000., 0.9, 0.01);
+    }
+
+    #[test]
+    #[should_panic]
+    fn choice_rejects_invalid_skew() {
+        Choice::crushed(1.5);
+    }
+
+    #[test]
+    fn choice_skip_ahead() {
+        let mut a = Choice::from_seed(0.5, 9);
+        let b = Choice::from_seed(0.5, 9);
+        a.skip_ahead(3);
+        assert!(a.take(100).eq(b.skip(3).take(100)));
+    }
+
+    #[test]
+    fn choice_run_length() {
+        let signs: Vec<f32> = Choice::run_length_from_seed(0.5, 4., 1)
+            .take(1_000_000)
+            .collect();
+        let runs = 1 + signs.windows(2).filter(|pair| pair[0] != pair[1]).count();
+        assert_close_enough!(1_000_000. / runs as f32, 4., 0.1);
+
+        let signs = Choice::run_length_from_seed(0.75, 4., 2).take(1_000_000);
+        let positive = signs.filter(|x| *x > 0.).count();
+        assert_close_enough!(positive as f32 / 1_000_000., 0.75, 0.01);
+
+        // the shortest runs allowed for this skew still keep the positive fraction
+        let signs = Choice::run_length_from_seed(0.1, 5., 3).take(1_000_000);
+        let positive = signs.filter(|x| *x > 0.).count();
+        assert_close_enough!(positive as f32 / 1_000_000., 0.1, 0.01);
+    }
+
+    #[test]
+    #[should_panic]
+    fn choice_run_length_too_short_for_skew() {
+        Choice::run_length(0.1, 1.);
+    }
+
+    #[test]
+    fn poisson_locations() {
+        let sample_rate = 44100;
+        let density = 441.;
+        let locations: Vec<usize> = PoissonImpulseLocations::new(density, sample_rate as f32, 3)
+            .take_while(|x| *x < 100 * sample_rate)
+            .collect();
+        assert_close_enough!(locations.len() as f32 / 100., density, 5.);
+        assert!(locations.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // exponential intervals have a standard deviation equal to their mean, and exceed the
+        // mean with probability 1 / e
+        let intervals: Vec<f32> = locations
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]) as f32)
+            .collect();
+        let mean = intervals.iter().sum::<f32>() / intervals.len() as f32;
+        assert_close_enough!(mean, 100., 2.);
+        let variance =
+            intervals.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / intervals.len() as f32;
+        assert_close_enough!(variance.sqrt(), mean, 3.);
+        let above_mean = intervals.iter().filter(|x| **x > mean).count();
+        assert_close_enough!(
+            above_mean as f32 / intervals.len() as f32,
+            (-1f32).exp(),
+            0.02
+        );
+    }
+
+    #[test]
+    fn arn_equivalent_spacing() {
+        let sample_rate = 44100;
+        let ovn_density = 441;
+        let (density, delta) = arn_equivalent(ovn_density);
+
+        let n = 10_000;
+        let ovn = OVNImpulseLocations::from_seed(ovn_density, sample_rate, 1);
+        let arn = ARNImpulseLocations::from_seed(density, sample_rate as f32, delta, 1);
+        let spacings = |locations: Vec<usize>| -> Vec<f32> {
+            locations
+                .windows(2)
+                .map(|pair| (pair[1] - pair[0]) as f32)
+                .collect()
+        };
+        let ovn_spacing = spacings(ovn.take(n).collect());
+        let arn_spacing = spacings(arn.take(n).collect());
+
+        let mean = |x: &[f32]| x.iter().sum::<f32>() / x.len() as f32;
+        let td = (sample_rate / ovn_density) as f32;
+        assert_close_enough!(mean(&arn_spacing), td, 1.);
+        assert_close_enough!(mean(&ovn_spacing), td, 1.);
+
+        let spread = |x: &[f32]| {
+            let m = mean(x);
+            (x.iter().map(|s| (s - m) * (s - m)).sum::<f32>() / x.len() as f32).sqrt()
+        };
+        assert_close_enough!(spread(&arn_spacing) / spread(&ovn_spacing), 1., 0.05);
+    }
+
+    #[test]
+    fn kernel_from_locations() {
+        let kernel = VelvetNoiseKernel::from_locations(vec![10, 25, 40], Choice::classic());
+        let pairs: Vec<(usize, f32)> = kernel.collect();
+        assert_eq!(
+            pairs
+                .iter()
+                .map(|(idx, _coeff)| *idx)
+                .collect::<Vec<usize>>(),
+            vec![10, 25, 40]
+        );
+        assert!(pairs.iter().all(|(_idx, coeff)| coeff.abs() == 1.));
+    }
+
+    #[test]
+    fn iterator_ext_chains() {
+        let seconds: Vec<f32> = OVNImpulseLocations::from_seed(100, 1000, 2)
+            .until_sample(1000)
+            .as_seconds(1000)
+            .collect();
+        assert_eq!(seconds.len(), 100);
+        assert!(seconds.iter().all(|t| *t >= 0. && *t < 1.));
+
+        let quiet: Vec<f32> = OriginalVelvetNoise::new(2000., 96000., None)
+            .scaled(0.3)
+            .quantize(3)
+            .take(96000)
+            .collect();
+        assert!(quiet.iter().all(|x| *x == 0. || x.abs() == 0.25));
+        assert_eq!(quiet.iter().filter(|x| **x != 0.).count(), 2000);
+    }
+
+    #[test]
+    fn kernel_render_array() {
+        let render = || {
+            VelvetNoiseKernel::new(
+                OVNImpulseLocations::from_seed(100, 1000, 4),
+                Choice::from_seed(0.5, 5),
+            )
+        };
+        let expected = render().render(0, 50, 0.5);
+        assert_eq!(expected.len(), 5);
+
+        let (taps, count) = render().render_array::<f32, 8>(0, 50, 0.5);
+        assert_eq!(count, 5);
+        assert_eq!(&taps[..count], &expected[..]);
+        assert!(taps[count..].iter().all(|tap| *tap == (0, 0.)));
+
+        let (taps, count) = render().render_array::<f32, 3>(0, 50, 0.5);
+        assert_eq!(count, 3);
+        assert_eq!(&taps[..], &expected[..3]);
+    }
+
+    #[test]
+    fn set_skew_mid_stream() {
+        let mut noise = OriginalVelvetNoise::new(2000., 96000., Some(0.5));
+        assert_eq!(noise.skew(), Some(0.5));
+        let mut block = vec![0f32; 96000];
+        noise.fill(&mut block);
+        assert_close_enough!(block.iter().sum::<f32>() / 2000., 0., 0.1);
+
+        noise.set_skew(0.9);
+        assert_eq!(noise.skew(), Some(0.9));
+        noise.fill(&mut block);
+        assert_close_enough!(block.iter().sum::<f32>() / 2000., 0.8, 0.1);
+    }
+
+    #[test]
+    fn haas_stereo_delays_right() {
+        let delay = 7;
+        let frames: Vec<[f32; 2]> =
+            haas_stereo(OriginalVelvetNoise::new(2000., 44100., None), delay)
+                .take(4410)
+                .collect();
+        assert!(frames[..delay].iter().all(|[_left, right]| *right == 0.));
+        for n in delay..frames.len() {
+            assert_eq!(frames[n][1], frames[n - delay][0]);
+        }
+        assert!(frames.iter().any(|[left, _right]| *left != 0.));
+    }
+
+    #[test]
+    fn amplitude_lfo_per_window() {
+        // 10 windows per LFO cycle, silent at the start of each cycle
+        let lfo = |m: usize| (std::f32::consts::PI * (m % 10) as f32 / 10.).sin();
+        let samples: Vec<f32> = VelvetNoise::with_amplitude_lfo(441., 44100., lfo)
+            .take(44100)
+            .collect();
+
+        for (m, window) in samples.chunks(100).enumerate() {
+            let peak = window.iter().fold(0f32, |peak, x| peak.max(x.abs()));
+            assert_close_enough!(peak, lfo(m), 1e-6);
+        }
+        let peak = samples.iter().fold(0f32, |peak, x| peak.max(x.abs()));
+        assert_eq!(peak, 1.);
+    }
+
+    #[test]
+    fn rate_change_keeps_density() {
+        let mut noise = RateAdaptingVelvet::from_seed(480., 48000., 0);
+        let impulses =
+            |noise: &mut RateAdaptingVelvet, n| noise.take(n).filter(|x| *x != 0.).count();
+        assert_eq!(impulses(&mut noise, 48000), 480);
+        assert_eq!(impulses(&mut noise, 48000), 480);
+
+        noise.set_sample_rate(96000.);
+        assert_eq!(impulses(&mut noise, 96000), 480);
+        assert_eq!(impulses(&mut noise, 96000), 480);
+
+        // windows are rounded down to whole samples
+        noise.set_sample_rate(44100.);
+        assert_close_enough!(impulses(&mut noise, 44100), 480, 5);
+    }
+
+    #[test]
+    fn decibel_conversions() {
+        assert_eq!(db_to_linear(0.), 1.);
+        assert_close_enough!(db_to_linear(-6.), 0.501, 0.001);
+        assert_close_enough!(linear_to_db(0.5), -6.02, 0.01);
+        assert_close_enough!(linear_to_db(db_to_linear(-30.)), -30., 1e-4);
+        assert_eq!(linear_to_db(0.), f32::NEG_INFINITY);
+
+        let render = || {
+            VelvetNoiseKernel::new(
+                OVNImpulseLocations::from_seed(441, 44100, 1),
+                Choice::from_seed(0.5, 2),
+            )
+        };
+        assert_eq!(
+            render().render_db(0, 4410, -6.),
+            render().render(0, 4410, db_to_linear(-6.))
+        );
+    }
+
     #[test]
     fn kernel_init() {
         let kern = VelvetNoiseKernel::new(OVNImpulseLocations::new(10, 20), Choice::classic());
@@ -357,6 +2281,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn render_exact_count() {
+        let kernel =
+            VelvetNoiseKernel::new(OVNImpulseLocations::new(441, 44100), Choice::classic())
+                .render_count(1000, 25, 0.5);
+        assert_eq!(kernel.len(), 25);
+        assert!(kernel
+            .iter()
+            .all(|(idx, coeff)| *idx >= 1000 && coeff.abs() == 0.5));
+    }
+
     #[test]
     fn noise_from_kernel() {
         let kernel = VelvetNoiseKernel::new(OVNImpulseLocations::new(10, 20), Choice::classic());
@@ -367,6 +2302,72 @@ mod tests {
         }
     }
 
+    #[test]
+    fn fill_continues_across_buffers() {
+        let density = 2000.;
+        let sample_rate = 96000.;
+        let mut noise = OriginalVelvetNoise::new(density, sample_rate, None);
+
+        let mut first = vec![0f32; 48000];
+        let mut second = vec![0f32; 48000];
+        noise.fill(&mut first);
+        noise.fill(&mut second);
+
+        let total: f32 = first.iter().chain(second.iter()).map(|s| s.abs()).sum();
+        assert_eq!(total, density);
+
+        // the iterator picks up where fill left off
+        let remaining: f32 = noise.take(96000).map(|s| s.abs()).sum();
+        assert_eq!(remaining, density);
+    }
+
+    #[test]
+    fn impulse_mask_matches_locations() {
+        let kernel = VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(441, 44100, 5),
+            Choice::classic(),
+        );
+        let mask: Vec<(usize, bool)> = VelvetNoise::from_kernel(kernel)
+            .impulse_mask()
+            .take(44100)
+            .collect();
+
+        let expected: Vec<usize> = OVNImpulseLocations::from_seed(441, 44100, 5)
+            .take_while(|loc| *loc < 44100)
+            .collect();
+        let marked: Vec<usize> = mask
+            .iter()
+            .filter(|(_n, is_impulse)| *is_impulse)
+            .map(|(n, _is_impulse)| *n)
+            .collect();
+
+        assert!(mask.iter().enumerate().all(|(i, (n, _))| i == *n));
+        assert_eq!(marked, expected);
+    }
+
+    #[test]
+    fn fixed_point_noise() {
+        let density = 2000;
+        let sample_rate = 96000;
+        let magnitude = 1 << 12;
+        let samples: Vec<i16> = VelvetNoiseI16::new(density, sample_rate, magnitude)
+            .take(sample_rate)
+            .collect();
+
+        assert!(samples
+            .iter()
+            .all(|s| [-magnitude, 0, magnitude].contains(s)));
+        assert_eq!(samples.iter().filter(|s| **s != 0).count(), density);
+        assert!(samples.contains(&magnitude));
+        assert!(samples.contains(&-magnitude));
+    }
+
+    #[test]
+    #[should_panic]
+    fn fixed_point_noise_rejects_min_magnitude() {
+        VelvetNoiseI16::new(2000, 96000, i16::MIN);
+    }
+
     #[test]
     fn iter_noise_samples() {
         // Check that a snippet of velvet noise contains at least one each of -1. and 1., and that
@@ -378,15 +2379,12 @@ mod tests {
         let sample_rate: f32 = 96000.;
         let take_n = sample_rate as usize;
 
-        let noise = original_velvet_noise(density, sample_rate);
+        let noise = OriginalVelvetNoise::new(density, sample_rate, None);
         let samples: Vec<f32> = noise.take(take_n).collect();
 
         assert_eq!(samples.iter().map(|s| *s as i32).max(), Some(1));
         assert_eq!(samples.iter().map(|s| *s as i32).min(), Some(-1));
-        assert_eq!(
-            samples.iter().map(|s| (*s).abs()).sum::<f32>(),
-            density as f32
-        );
+        assert_eq!(samples.iter().map(|s| (*s).abs()).sum::<f32>(), density);
 
         // save(sample_rate as u32, samples, "iter_noise_samples.wav");
     }
@@ -403,7 +2401,7 @@ mod tests {
         let crush_factor = 0.75;
         let take_n = sample_rate as usize;
 
-        let noise = crushed_original_velvet_noise(density, sample_rate, crush_factor);
+        let noise = OriginalVelvetNoise::new(density, sample_rate, Some(crush_factor));
         let samples: Vec<f32> = noise.take(take_n).collect();
 
         assert_eq!(samples.iter().cloned().fold(f32::NAN, f32::max), 1.);
@@ -436,6 +2434,22 @@ mod tests {
         // save(sample_rate as u32, samples, "iter_crushed_arn_noise_samples.wav");
     }
 
+    #[test]
+    fn mix_signals() {
+        let a = vec![0.25; 10].into_iter();
+        let b = std::iter::repeat(0.5);
+        let mixed: Vec<f32> = mix(a, b).collect();
+        assert_eq!(mixed, vec![0.75; 10]);
+
+        let layered = mix(
+            OriginalVelvetNoise::new(2000., 96000., None),
+            OriginalVelvetNoise::new(500., 96000., None),
+        );
+        for sample in layered.take(96000) {
+            assert!([-2., -1., 0., 1., 2.].contains(&sample));
+        }
+    }
+
     #[test]
     fn readme() {
         let density = 2000;
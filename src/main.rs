@@ -3,12 +3,7 @@ use dasp_sample::{Sample, I24};
 use dasp_signal::{self as signal, Signal};
 use hound::WavReader;
 use std::{env, fs, io};
-
-fn convolve_kern<F: Frame<Sample = f32>>(samples: &[F], kern: &[(usize, f32)]) -> F {
-    kern.iter().fold(F::EQUILIBRIUM, |accumulator, (i, x)| {
-        accumulator.add_amp(samples[*i].scale_amp(*x))
-    })
-}
+use velvet_noise::convolve::{convolve_kern, MovingKernel};
 
 fn i16_conv(x: i32) -> f32 {
     (x as i16).to_sample::<f32>()
@@ -22,14 +17,13 @@ fn default_conv(_x: i32) -> f32 {
     panic!("Unsupported wav format");
 }
 
-fn process<I, O>(reader: WavReader<io::BufReader<fs::File>>, out_file: &str)
+fn process<O>(reader: WavReader<io::BufReader<fs::File>>, out_file: &str)
 where
-    I: Sample,
     O: Frame<Sample = f32>,
 {
     // read samples from file
     // TODO: make this generic over channels and sample type
-    let spec = reader.spec().clone();
+    let spec = reader.spec();
     let duration = reader.duration();
 
     let map_func = match spec.bits_per_sample {
@@ -52,15 +46,15 @@ where
     let density = 32. / duration_s;
 
     // initialise an array of delay taps
-    let mut taps = velvet_noise::VelvetNoiseKernel(
+    let taps = velvet_noise::VelvetNoiseKernel::new(
         velvet_noise::OVNImpulseLocations::new(density as usize, sample_rate as usize),
         velvet_noise::Choice::classic(),
     )
     .take_while(|(i, _)| i < &samples.len())
     .collect::<Vec<(usize, f32)>>();
 
-    // used for subsequent convolution coefficients since the previous kernel is already taken
-    let mut choice = velvet_noise::Choice::classic();
+    // taps that fall off the end are respawned at the start with new coefficients
+    let mut kernel = MovingKernel::new(taps, samples.len() - 1, velvet_noise::Choice::classic());
 
     // output
     let spec = hound::WavSpec {
@@ -71,28 +65,17 @@ where
     };
     let mut writer = hound::WavWriter::create(out_file, spec).unwrap();
 
-    let max_index = samples.len() - 1;
     let gain = 0.1;
 
     for _ in 0..n_samples {
         // make a new frame and write it to the output file
-        let frame = convolve_kern(&samples, &taps).scale_amp(gain);
+        let frame = convolve_kern(&samples, kernel.taps()).scale_amp(gain);
         for sample in frame.channels() {
             writer.write_sample(sample).unwrap();
         }
 
         // move taps along delay line
-        for tap in taps.iter_mut() {
-            *tap = (tap.0 + 1, tap.1);
-        }
-
-        // create a new tap when one falls off the end
-        let length_before = taps.len();
-        taps.retain(|&tap| tap.0 <= max_index);
-        let length_after = taps.len();
-        for _ in 0..(length_before - length_after) {
-            taps.push((0, choice.next().unwrap()));
-        }
+        kernel.advance();
     }
 
     writer.finalize().unwrap();
@@ -109,8 +92,8 @@ pub fn main() {
     let reader = WavReader::open(args[1].as_str()).unwrap();
     let channels = reader.spec().channels;
     match channels {
-        1 => process::<i16, [f32; 1]>(reader, args[2].as_str()),
-        2 => process::<i16, [f32; 2]>(reader, args[2].as_str()),
+        1 => process::<[f32; 1]>(reader, args[2].as_str()),
+        2 => process::<[f32; 2]>(reader, args[2].as_str()),
         _ => {}
     }
 }
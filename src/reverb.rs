@@ -0,0 +1,585 @@
+//! Velvet noise reverb design as described in
+//! https://www.dafx.de/paper-archive/2013/papers/55.dafx2013_submission_54.pdf
+
+use crate::convolve::{RingConvolver, VelvetProcessor};
+use crate::filters::Biquad;
+use crate::kernel::{combine_kernels, densify, sparsify};
+use crate::{db_to_linear, Choice, OVNImpulseLocations, VelvetNoiseKernel};
+use std::f32::consts::PI;
+
+/// Extra gain applied to the first stage, in dB, used in the reverb example.
+/// Impulse responses with strong early reflections may need more.
+pub const DEFAULT_FIRST_STAGE_GAIN_BOOST_DB: f32 = 3.;
+
+/// Render and combine the kernels of a multi-stage velvet reverb.
+///
+/// Stage `i` covers indices `border_samples[i]..border_samples[i + 1]`. Density and gain both
+/// step down linearly from the maximum of `density_range` / `gain_range_db` (given as
+/// `(min, max)`) at the first stage towards the minimum at the last. The first stage is boosted
+/// by a further `first_stage_boost_db`, see `DEFAULT_FIRST_STAGE_GAIN_BOOST_DB`.
+pub fn multistage_kernel(
+    border_samples: &[usize],
+    density_range: (usize, usize),
+    gain_range_db: (f32, f32),
+    first_stage_boost_db: f32,
+    sample_rate: usize,
+    seed: u64,
+) -> Vec<(usize, f32)> {
+    let num_stages = border_samples.len().saturating_sub(1);
+    if num_stages == 0 {
+        return vec![];
+    }
+
+    let (min_density, max_density) = density_range;
+    let density_step = (max_density - min_density) / num_stages;
+
+    let (min_gain_db, max_gain_db) = gain_range_db;
+    let gain_step_db = (max_gain_db - min_gain_db) / num_stages as f32;
+
+    let kernels: Vec<Vec<(usize, f32)>> = (0..num_stages)
+        .map(|i| {
+            let density = max_density - (i * density_step);
+            let gain = if i == 0 {
+                db_to_linear(max_gain_db + first_stage_boost_db)
+            } else {
+                db_to_linear(max_gain_db - (i as f32 * gain_step_db))
+            };
+            let stage_seed = seed.wrapping_add(2 * i as u64);
+
+            VelvetNoiseKernel::new(
+                OVNImpulseLocations::from_seed(density, sample_rate, stage_seed),
+                Choice::from_seed(0.5, stage_seed.wrapping_add(1)),
+            )
+            .render(border_samples[i], border_samples[i + 1], gain)
+        })
+        .collect();
+
+    combine_kernels(&kernels)
+}
+
+/// Extra tail allowed for the allpass diffusers to ring out, in seconds
+const TAIL_MARGIN_SECONDS: f32 = 0.5;
+
+/// Number of samples of output needed after the input ends so the reverb tail of `kernel` is
+/// not truncated. This is the kernel's last index plus a margin for the allpass diffusers.
+pub fn estimated_tail_samples(kernel: &[(usize, f32)], sample_rate: usize) -> usize {
+    let max_idx = kernel.iter().map(|(idx, _coeff)| *idx).max().unwrap_or(0);
+    max_idx + (TAIL_MARGIN_SECONDS * sample_rate as f32) as usize
+}
+
+/// Impulse response made of a measured early IR followed by a velvet late tail.
+/// Samples of `early`, scaled by `gain`, are used below `crossover_sample` and taps of
+/// `late_kernel` from `crossover_sample` onwards.
+pub fn hybrid_ir(
+    early: &[f32],
+    late_kernel: &[(usize, f32)],
+    crossover_sample: usize,
+    gain: f32,
+) -> Vec<(usize, f32)> {
+    let early_end = crossover_sample.min(early.len());
+    sparsify(&early[..early_end])
+        .into_iter()
+        .map(|(idx, x)| (idx, x * gain))
+        .chain(
+            late_kernel
+                .iter()
+                .filter(|(idx, _coeff)| *idx >= crossover_sample)
+                .cloned(),
+        )
+        .collect()
+}
+
+/// Like `hybrid_ir`, but with a linear crossfade instead of a hard switch. Over
+/// `blend_start..blend_start + blend_samples` the `early` samples fade out while the taps of
+/// `late` fade in, with gains summing to 1. Earlier samples come from `early` alone and later
+/// ones from `late` alone. Taps landing on the same index are summed.
+pub fn blend_early_late(
+    early: &[f32],
+    late: &[(usize, f32)],
+    blend_start: usize,
+    blend_samples: usize,
+) -> Vec<(usize, f32)> {
+    let blend_end = blend_start + blend_samples;
+    // gain of the late part at idx, the early part gets the complement
+    let fade_in = |idx: usize| {
+        if idx < blend_start {
+            0.
+        } else if idx >= blend_end {
+            1.
+        } else {
+            (idx - blend_start) as f32 / blend_samples as f32
+        }
+    };
+
+    let early_end = blend_end.min(early.len());
+    let early: Vec<(usize, f32)> = sparsify(&early[..early_end])
+        .into_iter()
+        .map(|(idx, x)| (idx, x * (1. - fade_in(idx))))
+        .collect();
+    let late: Vec<(usize, f32)> = late
+        .iter()
+        .filter(|(idx, _coeff)| *idx >= blend_start)
+        .map(|(idx, coeff)| (*idx, coeff * fade_in(*idx)))
+        .filter(|(_idx, coeff)| *coeff != 0.)
+        .collect();
+    combine_kernels(&[early, late])
+}
+
+/// Schoeder allpass as in diagram at
+/// https://ccrma.stanford.edu/~jos/pasp/Allpass_Two_Combs.html
+/// b0 == aM == g
+#[derive(Clone, Debug)]
+pub struct AllPass {
+    buffer: Vec<f32>,
+    position: usize,
+    g: f32,
+}
+
+impl AllPass {
+    pub fn new(delay: usize, feedback: f32) -> Self {
+        Self {
+            buffer: vec![0f32; delay],
+            position: 0,
+            g: feedback,
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let delay = self.buffer[self.position];
+        let feedback = sample + (delay * -self.g);
+        self.buffer[self.position] = feedback;
+        self.position = (self.position + 1) % self.buffer.len();
+        let feedforward = feedback * self.g;
+        delay + feedforward
+    }
+}
+
+/// Schroeder allpass whose delay is swept by a sine LFO, for a chorused tail.
+/// The delay line is read with linear interpolation between samples.
+#[derive(Clone, Debug)]
+pub struct ModulatedAllPass {
+    buffer: Vec<f32>,
+    position: usize,
+    base_delay: f32,
+    depth: f32,
+    phase: f32,
+    phase_step: f32,
+    g: f32,
+}
+
+impl ModulatedAllPass {
+    /// base_delay and depth are in samples, with depth < base_delay
+    /// rate_hz is the LFO frequency
+    pub fn new(
+        base_delay: usize,
+        depth: usize,
+        rate_hz: f32,
+        feedback: f32,
+        sample_rate: usize,
+    ) -> Self {
+        assert!(depth < base_delay, "depth must be less than base_delay");
+        Self {
+            buffer: vec![0f32; base_delay + depth + 2],
+            position: 0,
+            base_delay: base_delay as f32,
+            depth: depth as f32,
+            phase: 0.,
+            phase_step: 2. * PI * rate_hz / sample_rate as f32,
+            g: feedback,
+        }
+    }
+
+    /// Delay in samples that will be used for the next sample
+    pub fn current_delay(&self) -> f32 {
+        self.base_delay + self.depth * self.phase.sin()
+    }
+
+    /// Read the delay line `delay` samples before the slot about to be written
+    fn read_delayed(&self, delay: f32) -> f32 {
+        let length = self.buffer.len();
+        let next = self.position + 1;
+        let whole = delay.floor() as usize;
+        let fraction = delay - whole as f32;
+        let newer = self.buffer[(next + length - whole) % length];
+        let older = self.buffer[(next + length - whole - 1) % length];
+        newer + (older - newer) * fraction
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let delay = self.read_delayed(self.current_delay());
+        self.phase = (self.phase + self.phase_step) % (2. * PI);
+
+        let feedback = sample + (delay * -self.g);
+        self.position = (self.position + 1) % self.buffer.len();
+        self.buffer[self.position] = feedback;
+        let feedforward = feedback * self.g;
+        delay + feedforward
+    }
+}
+
+/// Crossover frequencies in Hz between the low, mid and high bands of `band_decayed_kernel`
+pub const BAND_CROSSOVERS_HZ: (f32, f32) = (500., 4000.);
+
+/// Split a signal into low and high bands with 4th order Linkwitz-Riley filters, i.e. two
+/// cascaded Butterworth sections, so the bands sum to an allpass response
+fn linkwitz_riley_split(signal: &[f32], crossover: f32, sample_rate: f32) -> (Vec<f32>, Vec<f32>) {
+    let cascade = |filter: Biquad| -> Vec<f32> {
+        filter
+            .clone()
+            .apply(filter.apply(signal.iter().cloned()))
+            .collect()
+    };
+    (
+        cascade(Biquad::lowpass(crossover, 0.707, sample_rate)),
+        cascade(Biquad::highpass(crossover, 0.707, sample_rate)),
+    )
+}
+
+/// Apply frequency dependent decay to a kernel, as real rooms decay faster at high frequencies.
+///
+/// The densified kernel is split into low, mid and high bands at `BAND_CROSSOVERS_HZ` by a
+/// Linkwitz-Riley filterbank, whose bands sum back to a flat magnitude response. Each band is
+/// faded by an exponential envelope falling 60 dB over its entry of `t60s` (in seconds, ordered
+/// low, mid, high) and the bands are recombined. The result is dense.
+pub fn band_decayed_kernel(
+    kernel: &[(usize, f32)],
+    t60s: [f32; 3],
+    sample_rate: usize,
+) -> Vec<(usize, f32)> {
+    let length = kernel
+        .iter()
+        .map(|(idx, _coeff)| idx + 1)
+        .max()
+        .unwrap_or(0);
+    let ir = densify(kernel, length);
+
+    let sr = sample_rate as f32;
+    let (low_crossover, high_crossover) = BAND_CROSSOVERS_HZ;
+    let (low, upper) = linkwitz_riley_split(&ir, low_crossover, sr);
+    let (mid, high) = linkwitz_riley_split(&upper, high_crossover, sr);
+
+    let envelope = |t60: f32, n: usize| db_to_linear(-60. * n as f32 / (t60 * sr));
+    let [t60_low, t60_mid, t60_high] = t60s;
+    let decayed: Vec<f32> = (0..length)
+        .map(|n| {
+            low[n] * envelope(t60_low, n)
+                + mid[n] * envelope(t60_mid, n)
+                + high[n] * envelope(t60_high, n)
+        })
+        .collect();
+    sparsify(&decayed)
+}
+
+/// Allpass delays in samples, given on page 5
+pub const DEFAULT_ALLPASS_DELAYS: [usize; 7] = [1, 64, 140, 209, 442, 555, 630];
+
+/// Allpass feedback gain, given on page 5
+pub const DEFAULT_ALLPASS_FEEDBACK: f32 = 0.618;
+
+/// Streaming velvet reverb: sparse convolution with a kernel, e.g. from `multistage_kernel`,
+/// followed by a cascade of Schroeder allpass diffusers.
+#[derive(Clone, Debug)]
+pub struct VelvetReverb {
+    convolver: RingConvolver,
+    allpass_filters: Vec<AllPass>,
+    allpass_enabled: bool,
+    gain: f32,
+}
+
+impl VelvetReverb {
+    /// Reverb over `kernel` using the allpass cascade from the paper
+    pub fn new(kernel: Vec<(usize, f32)>) -> Self {
+        Self {
+            convolver: RingConvolver::new(kernel, None),
+            allpass_filters: DEFAULT_ALLPASS_DELAYS
+                .iter()
+                .map(|delay| AllPass::new(*delay, DEFAULT_ALLPASS_FEEDBACK))
+                .collect(),
+            allpass_enabled: true,
+            gain: 1.,
+        }
+    }
+
+    /// Linear gain applied to the output
+    pub fn with_gain(mut self, gain: f32) -> Self {
+        self.gain = gain;
+        self
+    }
+
+    /// As `with_gain`, in decibels
+    pub fn with_gain_db(self, gain_db: f32) -> Self {
+        self.with_gain(db_to_linear(gain_db))
+    }
+
+    /// Enable or bypass the allpass cascade. When bypassed, only the sparse convolution is
+    /// applied, which is useful for hearing the contribution of the diffusers.
+    pub fn with_allpass(mut self, enabled: bool) -> Self {
+        self.allpass_enabled = enabled;
+        self
+    }
+
+    /// Give the low, mid and high bands of the kernel their own decay times, see
+    /// `band_decayed_kernel`
+    pub fn with_band_decays(mut self, t60s: [f32; 3], sample_rate: usize) -> Self {
+        let kernel = band_decayed_kernel(self.convolver.kernel(), t60s, sample_rate);
+        self.convolver = RingConvolver::new(kernel, None);
+        self
+    }
+
+    /// Push one input sample and return the next output sample
+    pub fn process_sample(&mut self, input: f32) -> f32 {
+        let wet = self.convolver.process(input);
+        let diffused = if self.allpass_enabled {
+            self.allpass_filters
+                .iter_mut()
+                .fold(wet, |sample, allpass| allpass.process(sample))
+        } else {
+            wet
+        };
+        diffused * self.gain
+    }
+
+    /// Process `input` into `output`, which must be the same length
+    pub fn process_block(&mut self, input: &[f32], output: &mut [f32]) {
+        assert_eq!(input.len(), output.len());
+        for (out, x) in output.iter_mut().zip(input.iter()) {
+            *out = self.process_sample(*x);
+        }
+    }
+}
+
+impl VelvetProcessor for VelvetReverb {
+    fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        self.process_block(input, output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::rt60;
+    use more_asserts::*;
+
+    #[test]
+    fn multistage_covers_every_stage() {
+        let borders = [4411, 5672, 7214, 9044, 11171, 13602];
+        let kernel = multistage_kernel(
+            &borders,
+            (40, 100),
+            (-30., 0.),
+            DEFAULT_FIRST_STAGE_GAIN_BOOST_DB,
+            44100,
+            0,
+        );
+
+        let stages_with_taps = borders
+            .windows(2)
+            .filter(|band| {
+                kernel
+                    .iter()
+                    .any(|(idx, _coeff)| *idx >= band[0] && *idx < band[1])
+            })
+            .count();
+        assert_eq!(stages_with_taps, borders.len() - 1);
+
+        for (idx, _coeff) in kernel.iter() {
+            assert!(*idx >= borders[0] && *idx < borders[borders.len() - 1]);
+        }
+    }
+
+    #[test]
+    fn first_stage_boost_scales_stage_zero() {
+        let borders = [4411, 5672, 7214, 9044];
+        let flat = multistage_kernel(&borders, (40, 100), (-30., 0.), 0., 44100, 7);
+        let boosted = multistage_kernel(&borders, (40, 100), (-30., 0.), 6., 44100, 7);
+        assert_eq!(flat.len(), boosted.len());
+
+        let boost = db_to_linear(6.);
+        for ((idx, a), (_, b)) in flat.iter().zip(boosted.iter()) {
+            if *idx < borders[1] {
+                assert!((b - a * boost).abs() < 1e-6);
+            } else {
+                assert_eq!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn tail_exceeds_kernel() {
+        let kernel = vec![(10, 1.), (90053, -0.1), (500, 0.5)];
+        assert_gt!(estimated_tail_samples(&kernel, 44100), 90053);
+    }
+
+    #[test]
+    fn hybrid_ir_crossover() {
+        let early = [0.9, 0., -0.5, 0.25, 0., 0.1, 0.05];
+        let late = vec![(2, 1.), (4, -1.), (6, 1.), (9, -1.)];
+        let ir = hybrid_ir(&early, &late, 4, 0.5);
+        assert_eq!(
+            ir,
+            vec![
+                (0, 0.45),
+                (2, -0.25),
+                (3, 0.125),
+                (4, -1.),
+                (6, 1.),
+                (9, -1.)
+            ]
+        );
+    }
+
+    #[test]
+    fn blend_early_late_crossfades() {
+        let early = [0.8; 12];
+        let late = vec![(1, 1.), (4, -1.), (6, 1.), (7, -1.), (10, 1.), (13, -1.)];
+        let ir = blend_early_late(&early, &late, 4, 4);
+
+        // before the blend only early samples remain
+        assert_eq!(&ir[..4], &[(0, 0.8), (1, 0.8), (2, 0.8), (3, 0.8)]);
+        // in the blend both contributions are present with complementary fades
+        let expected = [
+            (4, 0.8),
+            (5, 0.8 * 0.75),
+            (6, 0.8 * 0.5 + 1. * 0.5),
+            (7, 0.8 * 0.25 - 1. * 0.75),
+        ];
+        for ((idx, coeff), (expected_idx, expected_coeff)) in ir[4..8].iter().zip(&expected) {
+            assert_eq!(idx, expected_idx);
+            assert!((coeff - expected_coeff).abs() < 1e-6);
+        }
+        // after the blend only late taps remain
+        assert_eq!(&ir[8..], &[(10, 1.), (13, -1.)]);
+    }
+
+    #[test]
+    fn allpass_preserves_impulse_energy() {
+        let mut allpass = AllPass::new(64, 0.618);
+        let energy: f32 = (0..10_000)
+            .map(|n| allpass.process(if n == 0 { 1. } else { 0. }))
+            .map(|x| x * x)
+            .sum();
+        assert_lt!((energy - 1.).abs(), 1e-4);
+    }
+
+    #[test]
+    fn unmodulated_allpass_matches_allpass() {
+        let mut allpass = AllPass::new(64, 0.618);
+        let mut modulated = ModulatedAllPass::new(64, 0, 0., 0.618, 44100);
+        for n in 0..1000 {
+            let x = (n as f32 * 0.1).sin();
+            assert_lt!((allpass.process(x) - modulated.process(x)).abs(), 1e-6);
+        }
+    }
+
+    #[test]
+    fn modulated_allpass_is_stable() {
+        let sample_rate = 44100;
+        let mut allpass = ModulatedAllPass::new(441, 20, 2., 0.618, sample_rate);
+
+        let delays: Vec<f32> = (0..sample_rate / 2)
+            .map(|_| {
+                let delay = allpass.current_delay();
+                allpass.process(0.);
+                delay
+            })
+            .collect();
+        let max = delays.iter().cloned().fold(f32::MIN, f32::max);
+        let min = delays.iter().cloned().fold(f32::MAX, f32::min);
+        let mean = delays.iter().sum::<f32>() / delays.len() as f32;
+        assert_lt!((max - 461.).abs(), 0.01);
+        assert_lt!((min - 421.).abs(), 0.01);
+        assert_lt!((mean - 441.).abs(), 0.1);
+
+        let output: Vec<f32> = (0..sample_rate)
+            .map(|n| allpass.process(if n == 0 { 1. } else { 0. }))
+            .collect();
+        assert!(output.iter().all(|x| x.is_finite() && x.abs() <= 1.));
+        let tail_energy: f32 = output[sample_rate / 2..].iter().map(|x| x * x).sum();
+        assert_lt!(tail_energy, 1e-6);
+    }
+
+    #[test]
+    fn process_sample_matches_process_block() {
+        let borders = [441, 1000, 2000, 4000];
+        let kernel = multistage_kernel(&borders, (400, 1000), (-20., 0.), 0., 44100, 3);
+        let input: Vec<f32> = (0..8000)
+            .map(|n| if n % 1500 == 0 { 1. } else { 0. })
+            .collect();
+
+        let mut block_reverb = VelvetReverb::new(kernel.clone());
+        let mut block_output = vec![0f32; input.len()];
+        block_reverb.process_block(&input, &mut block_output);
+
+        let mut sample_reverb = VelvetReverb::new(kernel);
+        let sample_output: Vec<f32> = input
+            .iter()
+            .map(|x| sample_reverb.process_sample(*x))
+            .collect();
+
+        assert_eq!(sample_output, block_output);
+        assert!(block_output.iter().any(|x| *x != 0.));
+    }
+
+    #[test]
+    fn bypassed_allpass_is_plain_convolution() {
+        let kernel = vec![(0, 0.5), (3, -1.), (20, 0.25), (64, 1.)];
+        let input: Vec<f32> = (0..2000).map(|n| (n as f32 * 0.1).sin()).collect();
+
+        let mut expected = vec![0f32; input.len()];
+        crate::convolve::convolve(&input, &kernel, &mut expected);
+
+        let mut dry = vec![0f32; input.len()];
+        VelvetReverb::new(kernel.clone())
+            .with_allpass(false)
+            .process_block(&input, &mut dry);
+        assert_eq!(dry, expected);
+
+        let mut diffused = vec![0f32; input.len()];
+        VelvetReverb::new(kernel).process_block(&input, &mut diffused);
+        assert_ne!(diffused, dry);
+    }
+
+    #[test]
+    fn high_band_decays_faster() {
+        let sample_rate = 32000;
+        let kernel = VelvetNoiseKernel::new(
+            OVNImpulseLocations::from_seed(4000, sample_rate, 8),
+            Choice::from_seed(0.5, 9),
+        )
+        .render(0, 2 * sample_rate, 1.);
+        let decayed = band_decayed_kernel(&kernel, [1.5, 0.8, 0.3], sample_rate);
+        let ir = densify(&decayed, 2 * sample_rate);
+
+        let band_rt60 = |filter: Biquad| {
+            let band: Vec<f32> = filter.apply(ir.iter().cloned()).collect();
+            rt60(&band, sample_rate)
+        };
+        let low = band_rt60(Biquad::lowpass(250., 0.707, sample_rate as f32));
+        let high = band_rt60(Biquad::highpass(10000., 0.707, sample_rate as f32));
+        assert_lt!((low - 1.5).abs(), 0.2);
+        assert_lt!((high - 0.3).abs(), 0.1);
+
+        let mut reverb = VelvetReverb::new(kernel).with_band_decays([1.5, 0.8, 0.3], sample_rate);
+        let output: Vec<f32> = (0..1000)
+            .map(|n| reverb.process_sample(if n == 0 { 1. } else { 0. }))
+            .collect();
+        assert!(output.iter().all(|x| x.is_finite()));
+    }
+
+    #[test]
+    fn reverb_gain_db() {
+        let kernel = vec![(0, 0.5), (3, -1.), (20, 0.25), (64, 1.)];
+        let input: Vec<f32> = (0..500).map(|n| (n as f32 * 0.1).sin()).collect();
+
+        let mut unity = vec![0f32; input.len()];
+        VelvetReverb::new(kernel.clone()).process_block(&input, &mut unity);
+        let mut quiet = vec![0f32; input.len()];
+        VelvetReverb::new(kernel)
+            .with_gain_db(-6.)
+            .process_block(&input, &mut quiet);
+
+        for (a, b) in unity.iter().zip(quiet.iter()) {
+            assert_lt!((a * db_to_linear(-6.) - b).abs(), 1e-6);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Allocation-free velvet noise for audio callbacks
+
+use crate::VelvetRng;
+use rand::distributions::{Bernoulli, Distribution};
+use rand::{Rng, SeedableRng};
+
+/// Original velvet noise generator that is safe to run in a real-time audio callback.
+/// All memory is allocated in the constructor; `fill` and `reset` never touch the heap.
+/// The impulses written by the last `fill` are kept, relative to the start of that block, in a
+/// tap buffer preallocated for blocks of up to `max_block` samples.
+#[derive(Clone, Debug)]
+pub struct RtVelvet<R = VelvetRng> {
+    rng: R,
+    td: usize,
+    signs: Bernoulli,
+    window: usize,
+    position: usize,
+    next: (usize, f32),
+    taps: Vec<(usize, f32)>,
+    max_block: usize,
+}
+
+impl RtVelvet {
+    /// Reproducible generator for a given seed
+    pub fn from_seed(
+        density: usize,
+        sample_rate: usize,
+        skew: f64,
+        max_block: usize,
+        seed: u64,
+    ) -> Self {
+        RtVelvet::with_rng(
+            density,
+            sample_rate,
+            skew,
+            max_block,
+            VelvetRng::seed_from_u64(seed),
+        )
+    }
+}
+
+impl<R: Rng> RtVelvet<R> {
+    /// density is non-zero pulses per second
+    /// sample_rate is total samples per second
+    /// skew is the probability of each impulse being positive
+    /// max_block is the longest buffer that will be passed to `fill`
+    pub fn with_rng(
+        density: usize,
+        sample_rate: usize,
+        skew: f64,
+        max_block: usize,
+        rng: R,
+    ) -> Self {
+        assert!((0. ..=1.).contains(&skew), "skew must be in [0, 1]");
+        let td = sample_rate / density;
+        assert!(td > 0, "density must not exceed sample_rate");
+        let mut rt = Self {
+            rng,
+            td,
+            signs: Bernoulli::new(skew).unwrap(),
+            window: 0,
+            position: 0,
+            next: (0, 0.),
+            // at most one impulse per window, and a block can touch one more window than fits
+            taps: Vec::with_capacity(max_block / td + 2),
+            max_block,
+        };
+        rt.next = rt.draw();
+        rt
+    }
+
+    fn draw(&mut self) -> (usize, f32) {
+        let idx = self.window * self.td + self.rng.gen_range(0, self.td);
+        self.window += 1;
+        let coeff = if self.signs.sample(&mut self.rng) {
+            1.
+        } else {
+            -1.
+        };
+        (idx, coeff)
+    }
+
+    /// Write the next `out.len()` samples into `out`, continuing from previous calls.
+    /// `out` must be no longer than `max_block`.
+    pub fn fill(&mut self, out: &mut [f32]) {
+        assert!(out.len() <= self.max_block, "block longer than max_block");
+        out.fill(0.);
+        self.taps.clear();
+
+        let end = self.position + out.len();
+        while self.next.0 < end {
+            let (idx, coeff) = (self.next.0 - self.position, self.next.1);
+            out[idx] = coeff;
+            self.taps.push((idx, coeff));
+            self.next = self.draw();
+        }
+
+        self.position = end;
+    }
+
+    /// Callback filling each buffer it is given via `fill`, e.g. for a `cpal` output stream.
+    /// Buffers must be no longer than `max_block`.
+    pub fn into_callback(mut self) -> impl FnMut(&mut [f32]) + Send
+    where
+        R: Send,
+    {
+        move |out: &mut [f32]| self.fill(out)
+    }
+
+    /// Impulses written by the last `fill`, relative to the start of that block
+    pub fn taps(&self) -> &[(usize, f32)] {
+        &self.taps
+    }
+
+    /// Restart from sample 0 with a new random number generator
+    pub fn reset(&mut self, rng: R) {
+        self.rng = rng;
+        self.window = 0;
+        self.position = 0;
+        self.taps.clear();
+        self.next = self.draw();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::observed_density;
+
+    #[test]
+    fn fill_keeps_density_across_blocks() {
+        let mut rt = RtVelvet::from_seed(441, 44100, 0.5, 64, 0);
+        let mut block = [0f32; 64];
+        let mut samples = vec![];
+        for _ in 0..(44100 * 4 / 64) {
+            rt.fill(&mut block);
+            assert_eq!(rt.taps().len(), block.iter().filter(|x| **x != 0.).count());
+            samples.extend_from_slice(&block);
+        }
+        assert!((observed_density(&samples[..44100], 44100) - 441.).abs() <= 1.);
+    }
+
+    #[test]
+    fn reset_restarts_sequence() {
+        let mut rt = RtVelvet::from_seed(441, 44100, 0.5, 512, 9);
+        let mut first = [0f32; 512];
+        let mut second = [0f32; 512];
+        rt.fill(&mut first);
+        rt.fill(&mut second);
+        rt.reset(VelvetRng::seed_from_u64(9));
+        rt.fill(&mut second);
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,118 @@
+//! Helpers for testing code built on the generators.
+//! Only available in this crate's tests or with the `testing` feature.
+
+use crate::VelvetRng;
+use rand::{RngCore, SeedableRng};
+
+/// Random number generator that logs every value it produces.
+///
+/// Pass it by `&mut` to a generator's `with_rng` constructor to inspect the draws afterwards.
+/// Whole words from `next_u32` and `next_u64` and bytes from `fill_bytes` are kept in separate
+/// logs, see `draws` and `filled_bytes`.
+#[derive(Clone, Debug)]
+pub struct RecordingRng<R = VelvetRng> {
+    inner: R,
+    draws: Vec<u64>,
+    filled_bytes: Vec<u8>,
+}
+
+impl RecordingRng {
+    /// Recording generator with a fixed seed
+    pub fn from_seed(seed: u64) -> RecordingRng {
+        RecordingRng::new(VelvetRng::seed_from_u64(seed))
+    }
+}
+
+impl<R: RngCore> RecordingRng<R> {
+    pub fn new(inner: R) -> RecordingRng<R> {
+        RecordingRng {
+            inner,
+            draws: vec![],
+            filled_bytes: vec![],
+        }
+    }
+
+    /// Every word produced by `next_u32` or `next_u64` so far, in order
+    pub fn draws(&self) -> &[u64] {
+        &self.draws
+    }
+
+    /// Every byte produced by `fill_bytes` so far, in order
+    pub fn filled_bytes(&self) -> &[u8] {
+        &self.filled_bytes
+    }
+}
+
+impl<R: RngCore> RngCore for RecordingRng<R> {
+    fn next_u32(&mut self) -> u32 {
+        let x = self.inner.next_u32();
+        self.draws.push(x as u64);
+        x
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let x = self.inner.next_u64();
+        self.draws.push(x);
+        x
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.inner.fill_bytes(dest);
+        self.filled_bytes.extend_from_slice(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OVNImpulseLocations;
+    use more_asserts::*;
+    use rand::Rng;
+
+    /// Plays back a log of recorded words
+    struct ReplayRng(std::vec::IntoIter<u64>);
+
+    impl RngCore for ReplayRng {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0
+                .next()
+                .expect("replayed more draws than were recorded")
+        }
+
+        fn fill_bytes(&mut self, _dest: &mut [u8]) {
+            unimplemented!()
+        }
+
+        fn try_fill_bytes(&mut self, _dest: &mut [u8]) -> Result<(), rand::Error> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn record_ovn_draws() {
+        let mut rng = RecordingRng::from_seed(3);
+        let td = 100;
+        let locations: Vec<usize> = OVNImpulseLocations::with_rng(441, 44100, &mut rng)
+            .take(50)
+            .collect();
+        assert!(rng.filled_bytes().is_empty());
+
+        // reducing the recorded words to [0, td) the way OVN does gives every impulse offset
+        let mut replay = ReplayRng(rng.draws().to_vec().into_iter());
+        for (m, location) in locations.iter().enumerate() {
+            let offset = replay.gen_range(0, td);
+            assert_lt!(offset, td);
+            assert_eq!(*location, m * td + offset);
+        }
+        assert_eq!(replay.0.len(), 0);
+    }
+}
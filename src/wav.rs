@@ -0,0 +1,216 @@
+//! Reading and writing WAV files with hound
+
+use crate::convolve::convolve;
+use crate::{OriginalVelvetNoise, VelvetError};
+use std::path::Path;
+
+/// Sample rate of an existing WAV file
+pub fn wav_sample_rate<P: AsRef<Path>>(path: P) -> hound::Result<u32> {
+    Ok(hound::WavReader::open(path)?.spec().sample_rate)
+}
+
+/// Original velvet noise generated at the sample rate of an existing WAV file, e.g. for
+/// layering texture over a recording. Returns the sample rate along with the generator.
+pub fn velvet_noise_matching_wav<P: AsRef<Path>>(
+    path: P,
+    density: f32,
+) -> hound::Result<(u32, OriginalVelvetNoise)> {
+    let sample_rate = wav_sample_rate(path)?;
+    Ok((
+        sample_rate,
+        OriginalVelvetNoise::new(density, sample_rate as f32, None),
+    ))
+}
+
+/// Read a WAV file as one `Vec` of samples in [-1, 1] per channel
+fn read_channels<P: AsRef<Path>>(path: P) -> hound::Result<(hound::WavSpec, Vec<Vec<f32>>)> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let interleaved: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let full_scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / full_scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+
+    let channels = spec.channels as usize;
+    let split = (0..channels)
+        .map(|c| {
+            interleaved
+                .iter()
+                .skip(c)
+                .step_by(channels)
+                .cloned()
+                .collect()
+        })
+        .collect();
+    Ok((spec, split))
+}
+
+/// Convolve every channel of a WAV file with `kernel`, scale by `gain`, soft clip with `tanh`
+/// and write the result, including the kernel's tail, as a 32 bit float WAV at the same rate.
+pub fn process_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    in_path: P,
+    out_path: Q,
+    kernel: &[(usize, f32)],
+    gain: f32,
+) -> Result<(), VelvetError> {
+    let (spec, channels) = read_channels(in_path)?;
+    let tail = kernel.iter().map(|(idx, _coeff)| *idx).max().unwrap_or(0);
+    let length = channels.first().map_or(0, |c| c.len()) + tail;
+
+    let processed: Vec<Vec<f32>> = channels
+        .iter()
+        .map(|input| {
+            let mut output = vec![0.; length];
+            convolve(input, kernel, &mut output);
+            output.iter().map(|x| (x * gain).tanh()).collect()
+        })
+        .collect();
+
+    let out_spec = hound::WavSpec {
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+        ..spec
+    };
+    let mut writer = hound::WavWriter::create(out_path, out_spec)?;
+    for n in 0..length {
+        for channel in processed.iter() {
+            writer.write_sample(channel[n])?;
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Linearly resample `samples` from one sample rate to another.
+/// The output covers the same duration, holding the last input sample where the output runs
+/// past it.
+pub fn resample(samples: &[f32], from: usize, to: usize) -> Vec<f32> {
+    if samples.is_empty() {
+        return vec![];
+    }
+    let length = (samples.len() * to).div_ceil(from);
+    let step = from as f64 / to as f64;
+    let last = samples.len() - 1;
+    (0..length)
+        .map(|n| {
+            let position = n as f64 * step;
+            let whole = position.floor() as usize;
+            if whole >= last {
+                return samples[last];
+            }
+            let fraction = (position - whole as f64) as f32;
+            samples[whole] + (samples[whole + 1] - samples[whole]) * fraction
+        })
+        .collect()
+}
+
+/// Write `n_samples` from a signal to a mono 32 bit float WAV file
+pub fn write_velvet_wav<P: AsRef<Path>>(
+    path: P,
+    gen: impl Iterator<Item = f32>,
+    n_samples: usize,
+    sample_rate: u32,
+) -> hound::Result<()> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for sample in gen.take(n_samples) {
+        writer.write_sample(sample)?;
+    }
+    writer.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_one_second() {
+        let path = std::env::temp_dir().join("velvet_noise_write_one_second.wav");
+        let noise = OriginalVelvetNoise::new(2000., 44100., None);
+        write_velvet_wav(&path, noise, 44100, 44100).unwrap();
+
+        let reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().sample_rate, 44100);
+        assert_eq!(reader.duration(), 44100);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resample_ramp() {
+        let ramp: Vec<f32> = (0..100).map(|n| n as f32 / 100.).collect();
+
+        let up = resample(&ramp, 100, 250);
+        assert_eq!(up.len(), 250);
+        assert_eq!(up[0], 0.);
+        assert_eq!(up[up.len() - 1], ramp[ramp.len() - 1]);
+        assert!((up[125] - 0.5).abs() < 1e-6);
+
+        let down = resample(&ramp, 100, 40);
+        assert_eq!(down.len(), 40);
+        assert_eq!(down[0], 0.);
+        assert!((down[39] - 39. / 40.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn noise_matches_wav_rate() {
+        let path = std::env::temp_dir().join("velvet_noise_noise_matches_wav_rate.wav");
+        write_velvet_wav(
+            &path,
+            OriginalVelvetNoise::new(2000., 48000., None),
+            480,
+            48000,
+        )
+        .unwrap();
+
+        let (sample_rate, noise) = velvet_noise_matching_wav(&path, 480.).unwrap();
+        assert_eq!(sample_rate, 48000);
+        let impulses = noise.take(48000).filter(|s| *s != 0.).count();
+        assert_eq!(impulses, 480);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn process_tiny_file() {
+        let in_path = std::env::temp_dir().join("velvet_noise_process_tiny_file_in.wav");
+        let out_path = std::env::temp_dir().join("velvet_noise_process_tiny_file_out.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&in_path, spec).unwrap();
+        for n in 0..100 {
+            writer
+                .write_sample(if n == 0 { 16384i16 } else { 0 })
+                .unwrap();
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let kernel = vec![(0, 1.), (10, -1.), (30, 1.)];
+        process_file(&in_path, &out_path, &kernel, 0.5).unwrap();
+
+        let mut reader = hound::WavReader::open(&out_path).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, 8000);
+        assert_eq!(reader.duration(), 130);
+        let samples: Vec<f32> = reader.samples::<f32>().map(Result::unwrap).collect();
+        assert!((samples[2 * 10] + 0.25f32.tanh()).abs() < 1e-6);
+        assert!(samples.iter().skip(1).step_by(2).all(|x| *x == 0.));
+
+        std::fs::remove_file(&in_path).unwrap();
+        std::fs::remove_file(&out_path).unwrap();
+    }
+}
@@ -0,0 +1,42 @@
+//! `RtVelvet::fill` and its callback must not allocate once constructed
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use velvet_noise::rt::RtVelvet;
+
+struct TrackingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: TrackingAllocator = TrackingAllocator;
+
+#[test]
+fn fill_does_not_allocate() {
+    // a single test, as allocations on other test threads would be counted too
+    let mut rt = RtVelvet::from_seed(2000, 44100, 0.5, 256, 0);
+    let mut callback = RtVelvet::from_seed(2000, 44100, 0.5, 256, 1).into_callback();
+    let mut block = [0f32; 256];
+
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    for _ in 0..1000 {
+        rt.fill(&mut block);
+        rt.fill(&mut block[..100]);
+        callback(&mut block);
+        callback(&mut block[..100]);
+    }
+    let after = ALLOCATIONS.load(Ordering::SeqCst);
+
+    assert_eq!(after, before);
+}